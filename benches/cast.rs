@@ -0,0 +1,85 @@
+//! Cast throughput against a real map.
+//!
+//! Run with `cargo bench` from the root, with the assets in place; the
+//! benchmarks panic if maps/test.bsp is missing or doesn't import. To compare
+//! against older collision code, run the same benchmarks on both commits; the
+//! rays are the same every time.
+
+#![feature(test)]
+
+extern crate test;
+extern crate vel0city;
+extern crate nalgebra as na;
+
+use test::Bencher;
+use vel0city::assets;
+use vel0city::map::{self, MASK_PLAYERSOLID};
+use vel0city::map::cast::Ray;
+
+const N_RAYS: usize = 1024;
+
+/// maps/test.bsp. Numbers from any other map wouldn't be comparable, so
+/// there's no fallback.
+fn load_map() -> map::Map {
+    let data = assets::load_bin_asset("maps/test.bsp")
+        .unwrap_or_else(|e| panic!("couldn't load maps/test.bsp for benchmarking: {}", e));
+    map::q3_import::import(&data)
+        .unwrap_or_else(|e| panic!("couldn't import maps/test.bsp for benchmarking: {:?}", e))
+}
+
+/// Deterministic rays of up to 512 units along each axis, starting somewhere in the map.
+fn make_rays(map: &map::Map, halfextents: na::Vec3<f32>) -> Vec<Ray> {
+    let world = &map.models[0];
+    let mut seed: u32 = 12345;
+    let mut rand = move || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    };
+    let mut lerp = |lo: f32, hi: f32| lo + (hi - lo) * rand();
+    (0..N_RAYS).map(|_| {
+        let orig = na::Pnt3::new(
+            lerp(world.mins.x, world.maxs.x),
+            lerp(world.mins.y, world.maxs.y),
+            lerp(world.mins.z, world.maxs.z));
+        let dir = na::Vec3::new(lerp(-512.0, 512.0), lerp(-512.0, 512.0), lerp(-512.0, 512.0));
+        Ray {
+            orig: orig,
+            dir: dir,
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        }
+    }).collect()
+}
+
+fn bench_casts(b: &mut Bencher, halfextents: na::Vec3<f32>) {
+    let map = load_map();
+    let rays = make_rays(&map, halfextents);
+    b.iter(|| {
+        let mut hits = 0;
+        for ray in &rays {
+            if map.cast_ray(ray).is_some() {
+                hits += 1;
+            }
+        }
+        test::black_box(hits)
+    });
+}
+
+#[bench]
+fn cast_player_boxes(b: &mut Bencher) {
+    bench_casts(b, vel0city::player::PLAYER_HALFEXTENTS);
+}
+
+#[bench]
+fn cast_points(b: &mut Bencher) {
+    bench_casts(b, na::zero());
+}
+
+/// The same rays as `cast_player_boxes`, all in one batch. Try it with and
+/// without `--features parallel`.
+#[bench]
+fn cast_player_boxes_batched(b: &mut Bencher) {
+    let map = load_map();
+    let rays = make_rays(&map, vel0city::player::PLAYER_HALFEXTENTS);
+    b.iter(|| test::black_box(map.cast_rays(&rays)));
+}
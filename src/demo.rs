@@ -0,0 +1,363 @@
+//! Recording and playback of the inputs that drove a game, one per tick.
+//!
+//! Demos are little-endian: a header naming the map and the movement settings,
+//! followed by every tick's input. Since `Game::step` is deterministic, feeding
+//! the inputs back in reproduces the run exactly.
+
+use byteorder::{self, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+use std;
+use na::{self, Rotation};
+use map::{Map, SurfaceFlags};
+use player::movement::MoveInput;
+use settings::{MoveSettings, SurfaceMods};
+use Game;
+
+const DEMO_MAGIC: &'static [u8; 4] = b"V0DM";
+/// Bump this whenever the format changes, e.g. when `MoveSettings` gains a field.
+pub const DEMO_VERSION: u32 = 4;
+
+const INPUT_JUMP: u8 = 0b01;
+const INPUT_SPECIAL: u8 = 0b10;
+
+#[derive(Debug)]
+pub enum DemoError {
+    ByteOrderError(byteorder::Error),
+    IoError(io::Error),
+    NotUtf8(std::string::FromUtf8Error),
+    /// Doesn't start with "V0DM".
+    BadMagic,
+    /// Written by a different version of the game.
+    UnsupportedVersion(u32),
+}
+impl std::convert::From<byteorder::Error> for DemoError {
+    fn from(e: byteorder::Error) -> DemoError {
+        DemoError::ByteOrderError(e)
+    }
+}
+impl std::convert::From<io::Error> for DemoError {
+    fn from(e: io::Error) -> DemoError {
+        DemoError::IoError(e)
+    }
+}
+impl std::convert::From<std::string::FromUtf8Error> for DemoError {
+    fn from(e: std::string::FromUtf8Error) -> DemoError {
+        DemoError::NotUtf8(e)
+    }
+}
+
+/// One tick of input, in the exact form it gets stored in a demo.
+///
+/// View angles are kept as pitch and yaw rather than a quaternion, so a
+/// recorded input turns into exactly the same `MoveInput` as the live one did.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TickInput {
+    /// Relative to the view angles, just like `MoveInput::wishvel`.
+    pub wishvel: na::Vec3<f32>,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub jump: bool,
+    pub special: bool,
+}
+impl TickInput {
+    pub fn get_ang(&self) -> na::UnitQuat<f32> {
+        na::UnitQuat::new(na::Vec3::new(0.0, self.yaw, 0.0))
+            .append_rotation(&na::Vec3::new(self.pitch, 0.0, 0.0))
+    }
+
+    pub fn to_moveinput(&self) -> MoveInput {
+        MoveInput {
+            wishvel: self.wishvel,
+            eyeang: self.get_ang(),
+            jump: self.jump,
+            special: self.special,
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), DemoError> {
+        try!(w.write_f32::<LittleEndian>(self.wishvel.x));
+        try!(w.write_f32::<LittleEndian>(self.wishvel.y));
+        try!(w.write_f32::<LittleEndian>(self.wishvel.z));
+        try!(w.write_f32::<LittleEndian>(self.pitch));
+        try!(w.write_f32::<LittleEndian>(self.yaw));
+        let mut flags = 0;
+        if self.jump {
+            flags |= INPUT_JUMP;
+        }
+        if self.special {
+            flags |= INPUT_SPECIAL;
+        }
+        try!(w.write_u8(flags));
+        Ok(())
+    }
+
+    fn read<R: Read>(r: &mut R) -> Result<TickInput, DemoError> {
+        let x = try!(r.read_f32::<LittleEndian>());
+        let y = try!(r.read_f32::<LittleEndian>());
+        let z = try!(r.read_f32::<LittleEndian>());
+        let pitch = try!(r.read_f32::<LittleEndian>());
+        let yaw = try!(r.read_f32::<LittleEndian>());
+        let flags = try!(r.read_u8());
+        Ok(TickInput {
+            wishvel: na::Vec3::new(x, y, z),
+            pitch: pitch,
+            yaw: yaw,
+            jump: flags & INPUT_JUMP != 0,
+            special: flags & INPUT_SPECIAL != 0,
+        })
+    }
+}
+
+/// FNV-1a, used to tell whether a demo was recorded on the same map file.
+pub fn hash_map_data(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn write_movesettings<W: Write>(w: &mut W, s: &MoveSettings) -> Result<(), DemoError> {
+    try!(w.write_f32::<LittleEndian>(s.gravity));
+    try!(w.write_f32::<LittleEndian>(s.accel));
+    try!(w.write_f32::<LittleEndian>(s.airaccel));
+    try!(w.write_f32::<LittleEndian>(s.speedeps));
+    try!(w.write_f32::<LittleEndian>(s.maxspeed));
+    try!(w.write_f32::<LittleEndian>(s.movespeed));
+    try!(w.write_f32::<LittleEndian>(s.airspeed));
+    try!(w.write_f32::<LittleEndian>(s.jumpspeed));
+    try!(w.write_f32::<LittleEndian>(s.friction));
+    try!(w.write_f32::<LittleEndian>(s.slidetime));
+    try!(w.write_f32::<LittleEndian>(s.specialcooldown));
+    try!(w.write_f32::<LittleEndian>(s.swimaccel));
+    try!(w.write_f32::<LittleEndian>(s.swimfriction));
+    try!(w.write_f32::<LittleEndian>(s.swimspeed));
+    try!(w.write_f32::<LittleEndian>(s.swimgravity));
+    try!(w.write_f32::<LittleEndian>(s.lavadamage));
+    try!(w.write_f32::<LittleEndian>(s.slimedamage));
+    try!(w.write_u8(s.keepcheckpointvel as u8));
+    try!(w.write_f32::<LittleEndian>(s.teleportspeed));
+    try!(w.write_u8(s.keepteleportspeed as u8));
+    try!(w.write_u32::<LittleEndian>(s.surfacemods.len() as u32));
+    for m in s.surfacemods.iter() {
+        try!(w.write_u32::<LittleEndian>(m.flags.bits()));
+        try!(w.write_f32::<LittleEndian>(m.friction));
+        try!(w.write_f32::<LittleEndian>(m.accel));
+    }
+    Ok(())
+}
+
+fn read_surfacemods<R: Read>(r: &mut R) -> Result<Vec<SurfaceMods>, DemoError> {
+    let count = try!(r.read_u32::<LittleEndian>());
+    let mut mods = vec![];
+    for _ in 0..count {
+        mods.push(SurfaceMods {
+            flags: SurfaceFlags::from_bits_truncate(try!(r.read_u32::<LittleEndian>())),
+            friction: try!(r.read_f32::<LittleEndian>()),
+            accel: try!(r.read_f32::<LittleEndian>()),
+        });
+    }
+    Ok(mods)
+}
+
+fn read_movesettings<R: Read>(r: &mut R) -> Result<MoveSettings, DemoError> {
+    Ok(MoveSettings {
+        gravity: try!(r.read_f32::<LittleEndian>()),
+        accel: try!(r.read_f32::<LittleEndian>()),
+        airaccel: try!(r.read_f32::<LittleEndian>()),
+        speedeps: try!(r.read_f32::<LittleEndian>()),
+        maxspeed: try!(r.read_f32::<LittleEndian>()),
+        movespeed: try!(r.read_f32::<LittleEndian>()),
+        airspeed: try!(r.read_f32::<LittleEndian>()),
+        jumpspeed: try!(r.read_f32::<LittleEndian>()),
+        friction: try!(r.read_f32::<LittleEndian>()),
+        slidetime: try!(r.read_f32::<LittleEndian>()),
+        specialcooldown: try!(r.read_f32::<LittleEndian>()),
+        swimaccel: try!(r.read_f32::<LittleEndian>()),
+        swimfriction: try!(r.read_f32::<LittleEndian>()),
+        swimspeed: try!(r.read_f32::<LittleEndian>()),
+        swimgravity: try!(r.read_f32::<LittleEndian>()),
+        lavadamage: try!(r.read_f32::<LittleEndian>()),
+        slimedamage: try!(r.read_f32::<LittleEndian>()),
+        keepcheckpointvel: try!(r.read_u8()) != 0,
+        teleportspeed: try!(r.read_f32::<LittleEndian>()),
+        keepteleportspeed: try!(r.read_u8()) != 0,
+        surfacemods: try!(read_surfacemods(r)),
+    })
+}
+
+/// A single player's inputs for every tick since it spawned.
+#[derive(Clone, Debug)]
+pub struct Demo {
+    /// The asset name of the map, e.g. "maps/test.bsp".
+    pub mapname: String,
+    /// `hash_map_data` of the map file it was recorded on.
+    pub maphash: u64,
+    pub movesettings: MoveSettings,
+    pub inputs: Vec<TickInput>,
+}
+impl Demo {
+    pub fn new(mapname: &str, mapdata: &[u8], movesettings: &MoveSettings) -> Demo {
+        Demo {
+            mapname: mapname.to_string(),
+            maphash: hash_map_data(mapdata),
+            movesettings: movesettings.clone(),
+            inputs: vec![],
+        }
+    }
+
+    /// Whether this demo was recorded on the given map file.
+    pub fn matches_map(&self, mapdata: &[u8]) -> bool {
+        self.maphash == hash_map_data(mapdata)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), DemoError> {
+        try!(w.write_all(DEMO_MAGIC));
+        try!(w.write_u32::<LittleEndian>(DEMO_VERSION));
+        try!(w.write_u32::<LittleEndian>(self.mapname.len() as u32));
+        try!(w.write_all(self.mapname.as_bytes()));
+        try!(w.write_u64::<LittleEndian>(self.maphash));
+        try!(write_movesettings(w, &self.movesettings));
+        try!(w.write_u32::<LittleEndian>(self.inputs.len() as u32));
+        for input in &self.inputs {
+            try!(input.write(w));
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Demo, DemoError> {
+        let mut magic = [0u8; 4];
+        for byte in magic.iter_mut() {
+            *byte = try!(r.read_u8());
+        }
+        if &magic != DEMO_MAGIC {
+            return Err(DemoError::BadMagic);
+        }
+        let version = try!(r.read_u32::<LittleEndian>());
+        if version != DEMO_VERSION {
+            return Err(DemoError::UnsupportedVersion(version));
+        }
+
+        let namelen = try!(r.read_u32::<LittleEndian>());
+        let mut name = vec![];
+        try!(r.by_ref().take(namelen as u64).read_to_end(&mut name));
+        if name.len() != namelen as usize {
+            return Err(DemoError::ByteOrderError(byteorder::Error::UnexpectedEOF));
+        }
+        let mapname = try!(String::from_utf8(name));
+        let maphash = try!(r.read_u64::<LittleEndian>());
+        let movesettings = try!(read_movesettings(r));
+
+        let n_inputs = try!(r.read_u32::<LittleEndian>());
+        let mut inputs = vec![];
+        for _ in 0..n_inputs {
+            inputs.push(try!(TickInput::read(r)));
+        }
+
+        Ok(Demo {
+            mapname: mapname,
+            maphash: maphash,
+            movesettings: movesettings,
+            inputs: inputs,
+        })
+    }
+
+    /// Starts a game that this demo can be played back into.
+    pub fn start_game(&self, map: Map) -> Game {
+        let mut game = Game::new(map, self.movesettings.clone());
+        game.add_player();
+        game
+    }
+
+    /// Plays the whole demo back on the given map.
+    pub fn play(&self, map: Map) -> Game {
+        let mut game = self.start_game(map);
+        for input in &self.inputs {
+            game.step(&[input.to_moveinput()]);
+        }
+        game
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Demo, DemoError, TickInput};
+    use std::io::Cursor;
+    use na;
+    use map::SURF_NODAMAGE;
+    use settings::{MoveSettings, SurfaceMods};
+    use Game;
+
+    fn recorded_demo(ticks: u32) -> (Demo, Game) {
+        let settings = MoveSettings {
+            surfacemods: vec![SurfaceMods { flags: SURF_NODAMAGE, friction: 0.5, accel: 2.0 }],
+            .. Default::default()
+        };
+        let mut demo = Demo::new("maps/floor.bsp", b"not really a map", &settings);
+        let mut game = demo.start_game(::test::floor_map());
+        for tick in 0..ticks {
+            let input = TickInput {
+                wishvel: na::Vec3::new(0.0, 0.0, -220.0),
+                pitch: 0.1,
+                yaw: tick as f32 * 0.013,
+                jump: tick % 50 < 10,
+                special: false,
+            };
+            demo.inputs.push(input);
+            game.step(&[input.to_moveinput()]);
+        }
+        (demo, game)
+    }
+
+    #[test]
+    fn roundtrips() {
+        let (demo, _) = recorded_demo(300);
+        let mut data = vec![];
+        demo.write(&mut data).unwrap();
+        let read = Demo::read(&mut Cursor::new(&data[..])).unwrap();
+
+        assert_eq!(read.mapname, demo.mapname);
+        assert_eq!(read.maphash, demo.maphash);
+        assert!(read.matches_map(b"not really a map"));
+        assert!(!read.matches_map(b"some other map"));
+        assert_eq!(read.movesettings.gravity, demo.movesettings.gravity);
+        assert_eq!(read.movesettings.surfacemods.len(), 1);
+        assert_eq!(read.movesettings.surfacemods[0].flags, SURF_NODAMAGE);
+        assert_eq!(read.movesettings.surfacemods[0].accel, 2.0);
+        assert_eq!(read.inputs, demo.inputs);
+    }
+
+    #[test]
+    fn playback_matches_recording() {
+        let (demo, live) = recorded_demo(600);
+        let mut data = vec![];
+        demo.write(&mut data).unwrap();
+        let played = Demo::read(&mut Cursor::new(&data[..])).unwrap().play(::test::floor_map());
+
+        assert_eq!(played.tick, live.tick);
+        assert_eq!(played.players[0].pos, live.players[0].pos);
+        assert_eq!(played.players[0].vel, live.players[0].vel);
+    }
+
+    #[test]
+    fn rejects_bad_demos() {
+        let (demo, _) = recorded_demo(10);
+        let mut data = vec![];
+        demo.write(&mut data).unwrap();
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        match Demo::read(&mut Cursor::new(&bad_magic[..])) {
+            Err(DemoError::BadMagic) => (),
+            x => panic!("{:?}", x)
+        }
+
+        let mut bad_version = data.clone();
+        bad_version[4] = 0xff;
+        match Demo::read(&mut Cursor::new(&bad_version[..])) {
+            Err(DemoError::UnsupportedVersion(_)) => (),
+            x => panic!("{:?}", x)
+        }
+
+        let truncated = &data[..data.len() - 3];
+        assert!(Demo::read(&mut Cursor::new(truncated)).is_err());
+    }
+}
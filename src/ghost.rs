@@ -0,0 +1,175 @@
+//! Trails of where a player went during a run, for racing against later.
+
+use na::{self, Rotate};
+use std::f32::consts::PI;
+use player::{Player, PlayerEvent};
+use run::RunState;
+
+/// Where a ghost is on a given tick of its run.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GhostFrame {
+    pub pos: na::Pnt3<f32>,
+    /// Heading in radians, in the same convention as `SpawnPoint::yaw`.
+    pub yaw: f32,
+}
+impl GhostFrame {
+    pub fn from_player(pl: &Player) -> GhostFrame {
+        let forward = pl.eyeang.rotate(&na::Vec3::new(0.0, 0.0, -1.0));
+        GhostFrame {
+            pos: pl.pos,
+            yaw: f32::atan2(-forward.x, -forward.z)
+        }
+    }
+}
+
+/// A finished run, one frame per tick from the start line to the goal.
+#[derive(Clone, Debug)]
+pub struct Ghost {
+    pub frames: Vec<GhostFrame>,
+    /// Length of the run, in ticks.
+    pub ticks: u32,
+}
+impl Ghost {
+    /// Where the ghost was `ticks` ticks into its run, interpolating between ticks.
+    /// The ghost waits at the goal once it has finished.
+    pub fn sample(&self, ticks: f32) -> Option<GhostFrame> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        let last = (self.frames.len() - 1) as f32;
+        let ticks = na::clamp(ticks, 0.0, last);
+        let idx = ticks.floor();
+        let frac = ticks - idx;
+        let a = self.frames[idx as usize];
+        let b = self.frames[f32::min(idx + 1.0, last) as usize];
+
+        // Turn the short way round.
+        let mut dyaw = (b.yaw - a.yaw) % (2.0 * PI);
+        if dyaw > PI {
+            dyaw -= 2.0 * PI;
+        } else if dyaw < -PI {
+            dyaw += 2.0 * PI;
+        }
+
+        Some(GhostFrame {
+            pos: (a.pos.to_vec() + (b.pos.to_vec() - a.pos.to_vec()) * frac).to_pnt(),
+            yaw: a.yaw + dyaw * frac
+        })
+    }
+}
+
+/// Follows a player through its runs, turning each completed one into a `Ghost`.
+pub struct GhostRecorder {
+    frames: Vec<GhostFrame>,
+}
+impl GhostRecorder {
+    pub fn new() -> GhostRecorder {
+        GhostRecorder {
+            frames: vec![]
+        }
+    }
+
+    /// Call once per tick, after the player has moved.
+    /// Returns the ghost of the run the player just finished, if any.
+    pub fn record(&mut self, pl: &Player) -> Option<Ghost> {
+        match pl.run {
+            RunState::Running { ticks, .. } => {
+                self.push(ticks, pl);
+                None
+            },
+            RunState::Finished(ref result) => {
+                let finished = pl.events.iter().any(|ev| match *ev {
+                    PlayerEvent::Finished(_) => true,
+                    _ => false
+                });
+                if !finished {
+                    return None;
+                }
+                self.push(result.ticks, pl);
+                if self.frames.len() == result.ticks as usize + 1 {
+                    Some(Ghost {
+                        frames: ::std::mem::replace(&mut self.frames, vec![]),
+                        ticks: result.ticks
+                    })
+                } else {
+                    // We didn't see the whole run.
+                    self.frames.clear();
+                    None
+                }
+            },
+            RunState::NotStarted => {
+                self.frames.clear();
+                None
+            }
+        }
+    }
+
+    fn push(&mut self, ticks: u32, pl: &Player) {
+        // Restarting the run (or standing in the start trigger) rewinds the trail.
+        self.frames.truncate(ticks as usize);
+        if self.frames.len() == ticks as usize {
+            self.frames.push(GhostFrame::from_player(pl));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ghost, GhostFrame, GhostRecorder};
+    use player::{Player, PlayerEvent};
+    use run::{RunResult, RunState};
+    use na;
+
+    #[test]
+    fn samples_between_ticks() {
+        let ghost = Ghost {
+            frames: vec![
+                GhostFrame { pos: na::Pnt3::new(0.0, 0.0, 0.0), yaw: 3.0 },
+                GhostFrame { pos: na::Pnt3::new(10.0, 0.0, 0.0), yaw: -3.0 },
+            ],
+            ticks: 1
+        };
+
+        let mid = ghost.sample(0.5).unwrap();
+        assert_eq!(mid.pos, na::Pnt3::new(5.0, 0.0, 0.0));
+        // Halfway between 3 and -3 the short way round is pi, not 0.
+        assert!(mid.yaw > 3.0);
+
+        assert_eq!(ghost.sample(-1.0).unwrap(), ghost.frames[0]);
+        assert_eq!(ghost.sample(5.0).unwrap(), ghost.frames[1]);
+        assert!(Ghost { frames: vec![], ticks: 0 }.sample(0.0).is_none());
+    }
+
+    #[test]
+    fn records_whole_runs() {
+        let mut recorder = GhostRecorder::new();
+        let mut pl: Player = Default::default();
+
+        // Standing in the start trigger keeps resetting the clock.
+        for _ in 0..3 {
+            pl.run = RunState::Running { start: 0, ticks: 0, splits: vec![] };
+            assert!(recorder.record(&pl).is_none());
+        }
+        for tick in 1..5 {
+            pl.pos = na::Pnt3::new(tick as f32, 0.0, 0.0);
+            pl.run = RunState::Running { start: 0, ticks: tick, splits: vec![] };
+            assert!(recorder.record(&pl).is_none());
+        }
+
+        let result = RunResult { ticks: 5, splits: vec![], tick: 1.0 };
+        pl.pos = na::Pnt3::new(5.0, 0.0, 0.0);
+        pl.run = RunState::Finished(result.clone());
+        pl.events = vec![PlayerEvent::Finished(result)];
+        let ghost = recorder.record(&pl).unwrap();
+
+        assert_eq!(ghost.ticks, 5);
+        assert_eq!(ghost.frames.len(), 6);
+        for (tick, frame) in ghost.frames.iter().enumerate() {
+            assert_eq!(frame.pos.x, tick as f32);
+        }
+
+        // The finished state sticks around, but only produces one ghost.
+        pl.events.clear();
+        assert!(recorder.record(&pl).is_none());
+    }
+}
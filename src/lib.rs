@@ -4,38 +4,130 @@
 extern crate nalgebra as na;
 extern crate byteorder;
 #[macro_use]
-extern crate glium;
-extern crate glutin;
-extern crate image;
-#[macro_use]
 extern crate bitflags;
 #[macro_use]
 extern crate rustc_serialize;
 
 extern crate vel0city_map;
 extern crate vel0city_base;
-extern crate vel0city_graphics;
 
 pub use vel0city_base::assets as assets;
 pub use vel0city_map as map;
-pub use vel0city_graphics as graphics;
 
-pub mod input;
+pub mod demo;
+pub mod ghost;
 pub mod player;
 pub mod particle;
+pub mod run;
 pub mod settings;
 
+/// Length of a simulation tick, in seconds.
+pub const TICK: f32 = 1.0 / 120.0;
+
 pub struct Game {
     pub map: map::Map,
     pub players: Vec<player::Player>,
 
     pub movesettings: settings::MoveSettings,
-    pub timescale: f32,
-    pub time: f32,
+    /// How many ticks have been simulated so far.
+    pub tick: u64,
+}
+impl Game {
+    pub fn new(map: map::Map, movesettings: settings::MoveSettings) -> Game {
+        Game {
+            map: map,
+            players: vec![],
+            movesettings: movesettings,
+            tick: 0,
+        }
+    }
+
+    /// Adds a player and spawns it, returning its index.
+    pub fn add_player(&mut self) -> u32 {
+        let idx = self.players.len() as u32;
+        self.players.push(Default::default());
+        player::movement::spawn_player(self, idx);
+        idx
+    }
+
+    /// Game time in seconds, derived from the tick counter so it never drifts.
+    pub fn time(&self) -> f32 {
+        (self.tick as f64 * TICK as f64) as f32
+    }
+
+    /// Advances the simulation by exactly one tick, with one input per player.
+    /// Identical inputs always produce identical results.
+    pub fn step(&mut self, inputs: &[player::movement::MoveInput]) {
+        assert_eq!(inputs.len(), self.players.len());
+
+        self.tick += 1;
+        let time = self.time();
+        self.map.update_movers(time);
+        for idx in 0..self.players.len() {
+            player::movement::push_player(self, idx as u32);
+        }
+        for (idx, input) in inputs.iter().enumerate() {
+            player::movement::move_player(self, idx as u32, input, TICK);
+        }
+        for pl in &self.players {
+            self.map.trigger_movers(time, &pl.pos, &pl.halfextents);
+        }
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::{map, Game, player};
+    use map::builder::MapBuilder;
+    use player::movement::MoveInput;
+    use na;
+
+    /// A big flat floor, with its top at y = 0.
+    pub fn floor_map() -> map::Map {
+        MapBuilder::new()
+            .add_box(na::Vec3::new(-4096.0, 0.0, -4096.0), na::Vec3::new(4096.0, 64.0, 4096.0))
+            .add_spawnpoint(na::Pnt3::new(0.0, -64.0, 0.0), 0.0)
+            .build()
+    }
+
+    fn input_for_tick(tick: u32) -> MoveInput {
+        MoveInput {
+            wishvel: na::Vec3::new(if tick % 90 < 45 { 200.0 } else { -200.0 }, 0.0, -200.0),
+            eyeang: na::UnitQuat::new(na::Vec3::new(0.0, tick as f32 * 0.01, 0.0)),
+            jump: tick % 60 < 20,
+            special: false,
+        }
+    }
+
+    fn simulate(ticks: u32) -> Game {
+        let mut game = Game::new(floor_map(), Default::default());
+        game.add_player();
+        for tick in 0..ticks {
+            game.step(&[input_for_tick(tick)]);
+        }
+        game
+    }
+
+    #[test]
+    fn step_counts_ticks() {
+        let game = simulate(240);
+        assert_eq!(game.tick, 240);
+        assert_eq!(game.time(), 2.0);
+    }
+
+    #[test]
+    fn step_is_deterministic() {
+        let a = simulate(600);
+        let b = simulate(600);
 
+        let (a, b) = (&a.players[0], &b.players[0]);
+        assert_eq!(a.pos, b.pos);
+        assert_eq!(a.vel, b.vel);
+        assert_eq!(a.flags, b.flags);
+        assert_eq!(a.landtime, b.landtime);
+        assert_eq!(a.holdjumptime, b.holdjumptime);
+        // The player should actually have gone somewhere, without falling through the floor.
+        assert!(a.pos.x != 0.0 || a.pos.z != 0.0);
+        assert!(a.pos.y <= 0.0);
+    }
 }
@@ -1,25 +1,70 @@
 use na;
+use map::Contents;
+use run::{
+    RunResult,
+    RunState
+};
 
 pub mod movement;
 
 // remove this later
 pub const PLAYER_HALFEXTENTS: na::Vec3<f32> = na::Vec3 { x: 8.0, y: 12.0, z: 8.0 };
 
+pub const PLAYER_MAX_HEALTH: f32 = 100.0;
+
 bitflags! {
     flags PlayerFlags: u32 {
         const PLAYER_ONGROUND = 0b00_00_00_01,
         const PLAYER_HOLDING_JUMP = 0b00_00_00_10,
         const PLAYER_CAN_STEP = 0b00_00_01_00,
         const PLAYER_MUST_DIE = 0b00_00_10_00,
+        /// Set for the tick on which the player was moved somewhere discontinuously
+        /// (e.g. by spawning). Clients should snap their view angles to the player's.
+        const PLAYER_TELEPORTED = 0b00_01_00_00,
     }
 }
 
+/// How deep in liquid the player is.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum WaterLevel {
+    Dry = 0,
+    /// Only the player's feet are in it.
+    Feet = 1,
+    /// Up to the player's middle.
+    Waist = 2,
+    /// Completely submerged.
+    Eyes = 3,
+}
+
 #[derive(Clone, Debug)]
 pub struct GrappleTarget {
     pos: na::Pnt3<f32>,
     dist: f32
 }
 
+/// Where the player was when it last reached a checkpoint.
+#[derive(Clone, Debug)]
+pub struct CheckpointState {
+    /// The checkpoint entity.
+    pub entity: u32,
+    pub pos: na::Pnt3<f32>,
+    pub eyeang: na::UnitQuat<f32>,
+    pub vel: na::Vec3<f32>,
+}
+
+/// Things that happened to a player during its last move.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlayerEvent {
+    /// Reached a checkpoint it wasn't already at.
+    Checkpoint {
+        entity: u32,
+        /// Split time in ticks, if the player is on a run.
+        split: Option<u32>
+    },
+    /// Touched the goal during a run.
+    Finished(RunResult),
+}
+
 pub struct Player {
     pub pos: na::Pnt3<f32>,
     pub flags: PlayerFlags,
@@ -30,7 +75,17 @@ pub struct Player {
     pub landtime: f32,
     pub holdjumptime: f32,
     
-    pub grapple: Option<GrappleTarget>
+    pub grapple: Option<GrappleTarget>,
+
+    pub waterlevel: WaterLevel,
+    /// The liquid the player's feet are in, if any.
+    pub watertype: Contents,
+    pub health: f32,
+
+    pub checkpoint: Option<CheckpointState>,
+    pub run: RunState,
+    /// Cleared at the start of every move.
+    pub events: Vec<PlayerEvent>,
 }
 impl Player {
     pub fn get_eyepos(&self) -> na::Pnt3<f32> {
@@ -48,7 +103,13 @@ impl Default for Player {
             eyeang: na::UnitQuat::new(na::Vec3::new(0.0, 0.0, 0.0)), 
             landtime: 0.0,
             holdjumptime: 0.0,
-            grapple: None
+            grapple: None,
+            waterlevel: WaterLevel::Dry,
+            watertype: Contents::empty(),
+            health: PLAYER_MAX_HEALTH,
+            checkpoint: None,
+            run: RunState::NotStarted,
+            events: vec![],
         }
     }
 }
@@ -2,15 +2,37 @@ use map::cast::{
     Ray,
     CastResult
 };
-use map::{EntityKind, Map};
+use map::mover::Mover;
+use map::{
+    EntityKind,
+    Map,
+    CONTENTS_LAVA,
+    CONTENTS_SLIME,
+    MASK_PLAYERSOLID,
+    MASK_SHOT,
+    MASK_WATER,
+    SURF_NOIMPACT,
+    SURF_SLICK,
+    SurfaceFlags
+};
 use player::{
+    CheckpointState,
     GrappleTarget,
     Player,
+    PlayerEvent,
     PlayerFlags,
+    WaterLevel,
+    PLAYER_MAX_HEALTH,
     PLAYER_ONGROUND,
     PLAYER_HOLDING_JUMP,
     PLAYER_CAN_STEP,
     PLAYER_MUST_DIE,
+    PLAYER_TELEPORTED,
+};
+use settings::MoveSettings;
+use run::{
+    RunResult,
+    RunState
 };
 use na::{
     self,
@@ -38,7 +60,31 @@ impl MoveInput {
         na::normalize(&horizwishvel) * na::norm(&wishvel)
     }
 }
-fn simple_move(map: &Map, pl: &mut Player, dt: f32) {
+/// Moves the player to `to`, remembering any triggers it passed through on the way.
+fn move_to(map: &Map, pl: &mut Player, to: na::Pnt3<f32>, touched: &mut Vec<u32>) {
+    let ray = Ray {
+        orig: pl.pos,
+        dir: to.to_vec() - pl.pos.to_vec(),
+        halfextents: pl.halfextents,
+        mask: MASK_PLAYERSOLID
+    };
+    for entidx in map.touch_triggers(&ray) {
+        if !touched.contains(&entidx) {
+            touched.push(entidx);
+        }
+    }
+    pl.pos = to;
+}
+
+/// How far the player may move in one substep, as a fraction of its smallest half-extent.
+const MAX_SUBSTEP_FRACTION: f32 = 0.5;
+/// Even at `MoveSettings::maxspeed`, this is more than enough.
+const MAX_SUBSTEPS: u32 = 32;
+
+/// Moves the player along its velocity for `dt`, splitting the move into substeps when
+/// it's going fast, so that it can't get wedged in corners or tunnel through thin brushes.
+fn simple_move(map: &Map, pl: &mut Player, dt: f32, touched: &mut Vec<u32>) {
+    // The grapple pulls the player back towards it, once per tick however many substeps there are.
     let localvel = if let Some(ref grapple) = pl.grapple {
             let grappledir = grapple.pos.to_vec() - pl.get_eyepos().to_vec();
 
@@ -51,10 +97,22 @@ fn simple_move(map: &Map, pl: &mut Player, dt: f32) {
             } else { na::zero() }
     } else { na::zero() };
 
+    let smallest = f32::min(pl.halfextents.x, f32::min(pl.halfextents.y, pl.halfextents.z));
+    let maxstep = f32::max(smallest * MAX_SUBSTEP_FRACTION, 0.5);
+    let substeps = na::clamp((na::norm(&(pl.vel + localvel)) * dt / maxstep).ceil() as u32, 1, MAX_SUBSTEPS);
+    for _ in 0..substeps {
+        simple_move_step(map, pl, localvel, dt / substeps as f32, touched);
+    }
+}
+
+/// Moves the player along its velocity plus `localvel` for `dt`, sliding along whatever it hits.
+/// `localvel` only lasts for this move, and doesn't end up in the player's velocity.
+fn simple_move_step(map: &Map, pl: &mut Player, localvel: na::Vec3<f32>, dt: f32, touched: &mut Vec<u32>) {
     let mut dt = dt;
     let mut numcontacts = 0;
     let mut contacts: [na::Vec3<f32>; 5] = [na::zero(); 5]; 
     let mut v = pl.vel + localvel;
+    let mut unstuck = false;
     pl.flags.remove(PLAYER_CAN_STEP);
 
     for _ in numcontacts..contacts.len() {
@@ -65,24 +123,25 @@ fn simple_move(map: &Map, pl: &mut Player, dt: f32) {
         let moveray = Ray {
             orig: pl.pos,
             dir: v * dt,
-            halfextents: pl.halfextents
+            halfextents: pl.halfextents,
+            mask: MASK_PLAYERSOLID
         };
 
         let cast = map.cast_ray(&moveray);
 
-        if let Some(CastResult { toi, norm, entity, .. }) = cast {
-            if let Some(entidx) = entity {
-                if map.entities[entidx as usize].kind == EntityKind::OutOfBounds {
-                    pl.flags.insert(PLAYER_MUST_DIE);
-                }
-                if map.entities[entidx as usize].kind == EntityKind::Goal {
-                    panic!("A WINNER IS YOU!");
-                }
+        if let Some(CastResult { start_solid: true, .. }) = cast {
+            if !unstuck {
+                unstuck = true;
+                unstick_player(map, pl);
+                continue;
             }
+        }
 
+        if let Some(CastResult { toi, norm, .. }) = cast {
             if toi > 0.0 {
                 numcontacts = 1;
-                pl.pos = pl.pos + (v * toi * dt); 
+                let newpos = pl.pos + (v * toi * dt);
+                move_to(map, pl, newpos, touched);
                 dt -= dt * toi;
                 if toi >= 1.0 {
                     break;
@@ -124,11 +183,140 @@ fn simple_move(map: &Map, pl: &mut Player, dt: f32) {
             v = na::zero(); 
         }
     }
-    pl.pos = pl.pos + v * dt;
+    // Whatever's left still has to be cast, or it could go right through something.
+    let (newpos, _) = how_far(map, pl, v * dt);
+    move_to(map, pl, newpos.to_pnt(), touched);
     pl.vel = v - localvel;
 
 }
 
+/// Moves a player that ended up inside something out of it, by as little as possible.
+fn unstick_player(map: &Map, pl: &mut Player) {
+    if let Some(offset) = map.unstick(&pl.pos, &pl.halfextents, MASK_PLAYERSOLID) {
+        pl.pos = pl.pos + offset;
+    }
+}
+
+fn respawn(map: &Map, movesettings: &MoveSettings, pl: &mut Player, playeridx: u32) {
+    if let Some(ref checkpoint) = pl.checkpoint {
+        pl.pos = checkpoint.pos;
+        pl.eyeang = checkpoint.eyeang;
+        pl.vel = if movesettings.keepcheckpointvel {
+            checkpoint.vel
+        } else {
+            na::zero()
+        };
+    } else {
+        let spawnpoint = map.get_spawnpoint(playeridx);
+        pl.pos = spawnpoint.pos;
+        pl.eyeang = spawnpoint.get_ang();
+        pl.vel = na::zero();
+        // Without a checkpoint to go back to, the run has to be started over.
+        pl.run = RunState::NotStarted;
+    }
+    unstick_player(map, pl);
+    pl.flags = PlayerFlags::empty(); 
+    pl.grapple = None;
+    pl.health = PLAYER_MAX_HEALTH;
+    set_waterlevel(map, pl);
+    // FIXME: need a better way to handle this
+    // without this, you slide when respawning
+    pl.flags.insert(PLAYER_ONGROUND);
+    pl.flags.insert(PLAYER_TELEPORTED);
+    pl.landtime = -movesettings.slidetime;
+}
+
+/// Puts a player at its spawn point, e.g. when it first joins the game.
+pub fn spawn_player(game: &mut Game, playeridx: u32) {
+    let pl = &mut game.players[playeridx as usize];
+    pl.checkpoint = None;
+    respawn(&game.map, &game.movesettings, pl, playeridx);
+}
+
+/// Reacts to the player touching a trigger. `now` is the current game tick, and `dt` how long it is.
+fn touch_entity(map: &Map, movesettings: &MoveSettings, pl: &mut Player, entidx: u32, now: u64, dt: f32) {
+    match map.entities[entidx as usize].kind {
+        EntityKind::OutOfBounds => {
+            pl.flags.insert(PLAYER_MUST_DIE);
+        },
+        EntityKind::Start => {
+            // This keeps happening while the player stands in the start trigger,
+            // so the clock really starts once they leave it.
+            pl.checkpoint = None;
+            pl.run = RunState::Running {
+                start: now,
+                ticks: 0,
+                splits: vec![]
+            };
+        },
+        EntityKind::Goal => {
+            let result = if let RunState::Running { start, ref splits, .. } = pl.run {
+                Some(RunResult {
+                    ticks: (now - start) as u32,
+                    splits: splits.clone(),
+                    tick: dt
+                })
+            } else {
+                None
+            };
+            if let Some(result) = result {
+                pl.events.push(PlayerEvent::Finished(result.clone()));
+                pl.run = RunState::Finished(result);
+            }
+        },
+        EntityKind::JumpPad { target } => {
+            pl.vel = jump_velocity(&pl.pos, &target, movesettings.gravity);
+            pl.flags.remove(PLAYER_ONGROUND);
+        },
+        EntityKind::Teleporter { dest, yaw } => {
+            let speed = if movesettings.keepteleportspeed {
+                na::norm(&pl.vel)
+            } else {
+                movesettings.teleportspeed
+            };
+            pl.pos = dest;
+            unstick_player(map, pl);
+            pl.eyeang = na::UnitQuat::new(na::Vec3::new(0.0, yaw, 0.0));
+            pl.vel = na::rotate(&pl.eyeang, &na::Vec3::new(0.0, 0.0, -speed));
+            pl.grapple = None;
+            pl.flags.remove(PLAYER_ONGROUND);
+            pl.flags.insert(PLAYER_TELEPORTED);
+        },
+        EntityKind::Checkpoint => {
+            if pl.checkpoint.as_ref().map(|c| c.entity) != Some(entidx) {
+                pl.checkpoint = Some(CheckpointState {
+                    entity: entidx,
+                    pos: pl.pos,
+                    eyeang: pl.eyeang,
+                    vel: pl.vel
+                });
+
+                let split = if let RunState::Running { start, ref mut splits, .. } = pl.run {
+                    let split = (now - start) as u32;
+                    splits.push(split);
+                    Some(split)
+                } else {
+                    None
+                };
+                pl.events.push(PlayerEvent::Checkpoint {
+                    entity: entidx,
+                    split: split
+                });
+            }
+        },
+        _ => ()
+    }
+}
+
+/// The velocity that takes something at `from` along an arc peaking at `apex`.
+fn jump_velocity(from: &na::Pnt3<f32>, apex: &na::Pnt3<f32>, gravity: f32) -> na::Vec3<f32> {
+    // Remember that -y is up. An apex below us can't be reached, so just hop towards it.
+    let height = f32::max(from.y - apex.y, 1.0);
+    let time = (2.0 * height / gravity).sqrt();
+    let horiz = na::Vec3::new(apex.x - from.x, 0.0, apex.z - from.z);
+    (horiz / time) + na::Vec3::new(0.0, -gravity * time, 0.0)
+}
+
 fn is_hanging_from_grapple(pl: &Player) -> bool {
     if let Some(ref grapple) = pl.grapple {
         na::norm(&(grapple.pos.to_vec() - pl.get_eyepos().to_vec())) >= grapple.dist
@@ -141,12 +329,97 @@ fn how_far(map: &Map, pl: &Player, movement: na::Vec3<f32>) -> (na::Vec3<f32>, O
     let trace = map.cast_ray(&Ray {
         orig: pl.pos,
         dir: movement,
-        halfextents: pl.halfextents
+        halfextents: pl.halfextents,
+        mask: MASK_PLAYERSOLID
     });
-    if let Some(trace) = trace {
-        (pl.pos.to_vec() + (movement * trace.toi), Some(trace.norm)) 
+    match trace {
+        // Getting out of something the player started in doesn't block it.
+        Some(trace) if trace.toi < 1.0 => (trace.endpos.to_vec(), Some(trace.norm)),
+        _ => (pl.pos.to_vec() + movement, None)
+    }
+}
+
+/// Carries a player standing on a mover along with it, and pushes it out of the way
+/// of movers running into it. Players that can't get out of the way get crushed.
+pub fn push_player(game: &mut Game, playeridx: u32) {
+    let pl = &mut game.players[playeridx as usize];
+    let map = &game.map;
+    for mover in &map.movers {
+        if na::approx_eq(&mover.delta, &na::zero()) {
+            continue;
+        }
+
+        // Was the player standing on it before it moved?
+        let before = Mover {
+            offset: mover.offset - mover.delta,
+            ..mover.clone()
+        };
+        let groundray = Ray {
+            orig: pl.pos,
+            dir: na::Vec3::new(0.0, 0.25, 0.0),
+            halfextents: pl.halfextents,
+            mask: MASK_PLAYERSOLID
+        };
+        let riding = match map.cast_mover(&before, &groundray) {
+            Some(CastResult { norm, .. }) => norm.y < -0.7,
+            None => false
+        };
+        if !riding && !map.mover_overlaps_box(mover, &pl.pos, &pl.halfextents) {
+            continue;
+        }
+
+        // Casts start out inside the mover, so they don't hit it.
+        let (to, blocked) = how_far(map, pl, mover.delta);
+        pl.pos = to.to_pnt();
+        if blocked.is_some() && map.mover_overlaps_box(mover, &pl.pos, &pl.halfextents) {
+            pl.flags.insert(PLAYER_MUST_DIE);
+        }
+    }
+}
+
+/// The friction and acceleration to use when standing on a floor with the given flags.
+fn ground_movement(settings: &MoveSettings, surface: SurfaceFlags) -> (f32, f32) {
+    // Slick floors have no friction, and only allow air control.
+    if surface.contains(SURF_SLICK) {
+        return (0.0, settings.airaccel);
+    }
+    match settings.surfacemods.iter().find(|m| surface.intersects(m.flags)) {
+        Some(m) => (settings.friction * m.friction, settings.accel * m.accel),
+        None => (settings.friction, settings.accel)
+    }
+}
+
+/// Works out how deep in liquid the player is, by checking its feet, middle and eyes.
+fn set_waterlevel(map: &Map, pl: &mut Player) {
+    let feet = pl.pos + na::Vec3::new(0.0, pl.halfextents.y - 1.0, 0.0);
+    let waist = pl.pos;
+    let eyes = pl.get_eyepos();
+    let liquid = |point: &na::Pnt3<f32>| map.point_contents(point).contents & MASK_WATER;
+
+    pl.watertype = liquid(&feet);
+    pl.waterlevel = if pl.watertype.is_empty() {
+        WaterLevel::Dry
+    } else if liquid(&waist).is_empty() {
+        WaterLevel::Feet
+    } else if liquid(&eyes).is_empty() {
+        WaterLevel::Waist
     } else {
-        (pl.pos.to_vec() + movement, None) 
+        WaterLevel::Eyes
+    };
+}
+
+/// Hurts a player standing in lava or slime, killing it once its health runs out.
+fn liquid_damage(movesettings: &MoveSettings, pl: &mut Player, dt: f32) {
+    let dps = if pl.watertype.contains(CONTENTS_LAVA) {
+        movesettings.lavadamage
+    } else if pl.watertype.contains(CONTENTS_SLIME) {
+        movesettings.slimedamage
+    } else {
+        return;
+    };
+    pl.health -= dps * (pl.waterlevel as u32 as f32) * dt;
+    if pl.health <= 0.0 {
+        pl.flags.insert(PLAYER_MUST_DIE);
     }
 }
 
@@ -155,25 +428,35 @@ fn horiz_speed(vel: &na::Vec3<f32>) -> f32 {
 }
 
 pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32) {
+    let time = game.time();
+    let now = game.tick;
     {
         let pl = &mut game.players[playeridx as usize];
 
         pl.eyeang = input.eyeang; 
+        pl.flags.remove(PLAYER_TELEPORTED);
+        pl.events.clear();
+
+        if let RunState::Running { start, ref mut ticks, .. } = pl.run {
+            *ticks = (now - start) as u32;
+        }
 
         if pl.flags.contains(PLAYER_MUST_DIE) {
-            pl.pos = na::Pnt3::new(0.0, 0.0, 0.0);
-            pl.eyeang = na::one();
-            pl.vel = na::zero();
-            pl.flags = PlayerFlags::empty(); 
-            pl.grapple = None;
-            // FIXME: need a better way to handle this
-            // without this, you slide when respawning
-            pl.flags.insert(PLAYER_ONGROUND);
-            pl.landtime = -game.movesettings.slidetime;
+            respawn(&game.map, &game.movesettings, pl, playeridx);
+        };
+
+        set_waterlevel(&game.map, pl);
+        liquid_damage(&game.movesettings, pl, dt);
+        // Deep enough to swim, rather than walk along the bottom.
+        let swimming = pl.waterlevel >= WaterLevel::Waist;
+        let gravity = if swimming {
+            game.movesettings.swimgravity
+        } else {
+            game.movesettings.gravity
         };
 
         if !pl.flags.contains(PLAYER_ONGROUND) {
-            pl.vel.y += game.movesettings.gravity * dt * 0.5;
+            pl.vel.y += gravity * dt * 0.5;
         }
 
         let stepsize = 2.8;
@@ -181,13 +464,15 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
         let downray = Ray {
             orig: pl.pos,
             dir: na::Vec3::new(0.0, 0.1, 0.0),
-            halfextents: pl.halfextents
+            halfextents: pl.halfextents,
+            mask: MASK_PLAYERSOLID
         };
 
         let cast = game.map.cast_ray(&downray);
 
         let (ground_normal, hit_floor) = if let Some(CastResult { norm, ..}) = cast {
-            if norm.y < -0.7 {
+            // Players being launched away from the floor (e.g. by a jump pad) aren't standing on it.
+            if norm.y < -0.7 && na::dot(&pl.vel, &norm) < 10.0 {
                 (Some(norm), true) 
             } else {
                 (Some(norm), false)
@@ -195,11 +480,15 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
         } else {
             (None, false)
         };
+        let ground_surface = match cast {
+            Some(CastResult { surface, .. }) if hit_floor => surface,
+            _ => SurfaceFlags::empty()
+        };
 
         if hit_floor {
             if !pl.flags.contains(PLAYER_ONGROUND) {
                 pl.flags.insert(PLAYER_ONGROUND);
-                pl.landtime = game.time; 
+                pl.landtime = time; 
             }
         } else {
             pl.flags.remove(PLAYER_ONGROUND);
@@ -210,11 +499,11 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
                 grapple.dist = f32::max(grapple.dist - (300.0 * dt), 30.0);
             }
 
-            if !pl.flags.contains(PLAYER_HOLDING_JUMP) || game.time < (pl.holdjumptime + game.movesettings.slidetime) {
+            if !pl.flags.contains(PLAYER_HOLDING_JUMP) || time < (pl.holdjumptime + game.movesettings.slidetime) {
                 if !pl.flags.contains(PLAYER_HOLDING_JUMP) {
-                    pl.holdjumptime = game.time;
+                    pl.holdjumptime = time;
                 }
-                if pl.flags.contains(PLAYER_ONGROUND) {
+                if pl.flags.contains(PLAYER_ONGROUND) && !swimming {
                     pl.flags.remove(PLAYER_ONGROUND);
                     let jspeed = game.movesettings.jumpspeed;
 
@@ -231,13 +520,17 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
                 let grappleray = Ray {
                     orig: pl.get_eyepos(),
                     dir: na::rotate(&pl.eyeang, &na::Vec3::new(0.0, 0.0, -4096.0)),
-                    halfextents: na::zero() 
+                    halfextents: na::zero(),
+                    mask: MASK_SHOT
                 };
 
                 let cast = game.map.cast_ray(&grappleray);
-                if let Some(CastResult { toi, .. }) = cast {
+                // The grapple doesn't stick to the sky and the like, or fire from inside walls.
+                // Getting out of a wall without hitting anything isn't something to stick to either.
+                let cast = cast.and_then(|c| if c.surface.contains(SURF_NOIMPACT) || c.all_solid || c.toi >= 1.0 { None } else { Some(c) });
+                if let Some(CastResult { toi, endpos, .. }) = cast {
                     pl.grapple = Some(GrappleTarget {
-                        pos: grappleray.orig + grappleray.dir * toi,
+                        pos: endpos,
                         dist: (na::norm(&grappleray.dir) * toi) + 30.0
                     });
                 }
@@ -246,8 +539,12 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
             pl.grapple = None;
         }
 
-        let accel = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) {
-            game.movesettings.accel
+        let (groundfriction, groundaccel) = ground_movement(&game.movesettings, ground_surface);
+
+        let accel = if swimming {
+            game.movesettings.swimaccel
+        } else if pl.flags.contains(PLAYER_ONGROUND) && time > (pl.landtime + game.movesettings.slidetime) {
+            groundaccel
         } else {
             if is_hanging_from_grapple(pl) {
                 game.movesettings.airaccel / 2.0 
@@ -255,13 +552,17 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
                 game.movesettings.airaccel
             }
         };
-        let friction = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) { 
-            game.movesettings.friction 
+        let friction = if swimming {
+            game.movesettings.swimfriction
+        } else if pl.flags.contains(PLAYER_ONGROUND) && time > (pl.landtime + game.movesettings.slidetime) { 
+            groundfriction
         } else {
             0.0
         };
 
-        let speedcap = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) { 
+        let speedcap = if swimming {
+            game.movesettings.swimspeed
+        } else if pl.flags.contains(PLAYER_ONGROUND) && time > (pl.landtime + game.movesettings.slidetime) { 
             game.movesettings.movespeed
         } else {
             game.movesettings.airspeed
@@ -284,7 +585,16 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
             pl.vel = dir * newspeed;
         }
 
-        let mut wishvel = input.get_abs_horiz_wishvel(); 
+        let mut wishvel = if swimming {
+            // Swim wherever the player is looking, and up while holding jump.
+            let mut wishvel = input.get_abs_wishvel();
+            if input.jump {
+                wishvel.y -= game.movesettings.swimspeed;
+            }
+            wishvel
+        } else {
+            input.get_abs_horiz_wishvel()
+        };
 
         if let Some(ground_normal) = ground_normal {
             clip_velocity(&mut wishvel, &ground_normal, 1.0); 
@@ -319,7 +629,8 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
 
         let startpos = pl.pos;
         let startvel = pl.vel;
-        simple_move(&game.map, pl, dt);
+        let mut downtouched = vec![];
+        simple_move(&game.map, pl, dt, &mut downtouched);
 
         let downpos = pl.pos;
         let downvel = pl.vel;
@@ -328,7 +639,8 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
         pl.vel = startvel;
         let (upstart, _) = how_far(&game.map, pl, na::Vec3::new(0.0, -stepsize, 0.0));
         pl.pos = upstart.to_pnt();
-        simple_move(&game.map, pl, dt);
+        let mut uptouched = vec![];
+        simple_move(&game.map, pl, dt, &mut uptouched);
 
         let (downstart, landnorm) = how_far(&game.map, pl, na::Vec3::new(0.0, stepsize , 0.0));
         pl.pos = downstart.to_pnt(); 
@@ -349,13 +661,20 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
             stepped = false;
         }
 
-        if !stepped {
+        let touched = if stepped {
+            uptouched
+        } else {
             pl.pos = downpos;
             pl.vel = downvel;
-        }
+            downtouched
+        };
 
         if !pl.flags.contains(PLAYER_ONGROUND) {
-            pl.vel.y += game.movesettings.gravity * dt * 0.5;
+            pl.vel.y += gravity * dt * 0.5;
+        }
+
+        for entidx in touched {
+            touch_entity(&game.map, &game.movesettings, pl, entidx, now, dt);
         }
 
     }
@@ -0,0 +1,35 @@
+/// Where a player is in a timed run from a start line to a goal.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunState {
+    NotStarted,
+    Running {
+        /// The game tick on which the player was last on the start line.
+        start: u64,
+        /// Ticks since leaving the start line, as of the player's last move.
+        ticks: u32,
+        /// Ticks at which each checkpoint was reached.
+        splits: Vec<u32>,
+    },
+    Finished(RunResult),
+}
+
+/// A completed run, ready to be shown or saved.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct RunResult {
+    /// Length of the whole run, in ticks.
+    pub ticks: u32,
+    /// Ticks at which each checkpoint was reached.
+    pub splits: Vec<u32>,
+    /// Length of a tick, in seconds.
+    pub tick: f32,
+}
+impl RunResult {
+    /// Length of the run, in seconds.
+    pub fn time(&self) -> f32 {
+        self.ticks as f32 * self.tick
+    }
+
+    pub fn split_times(&self) -> Vec<f32> {
+        self.splits.iter().map(|&split| split as f32 * self.tick).collect()
+    }
+}
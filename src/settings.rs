@@ -1,7 +1,18 @@
-use glutin::VirtualKeyCode;
 use std;
+use map::SurfaceFlags;
 
-#[derive(Clone)]
+/// Scales ground friction and acceleration on floors with particular surface flags.
+#[derive(Clone, Debug)]
+pub struct SurfaceMods {
+    /// Applies to floors with any of these flags.
+    pub flags: SurfaceFlags,
+    /// Multiplier for `MoveSettings::friction`.
+    pub friction: f32,
+    /// Multiplier for `MoveSettings::accel`.
+    pub accel: f32,
+}
+
+#[derive(Clone, Debug)]
 pub struct MoveSettings {
     /// The acceleration due to gravity.
     pub gravity: f32,
@@ -25,6 +36,32 @@ pub struct MoveSettings {
     pub slidetime: f32,
 
     pub specialcooldown: f32,
+
+    /// How fast players can accelerate while swimming.
+    pub swimaccel: f32,
+    pub swimfriction: f32,
+    /// Maximum player speed while swimming.
+    pub swimspeed: f32,
+    /// Replaces `gravity` while swimming, so players slowly sink.
+    pub swimgravity: f32,
+
+    /// Damage per second while in lava, multiplied by how deep the player is.
+    pub lavadamage: f32,
+    /// Damage per second while in slime, multiplied by how deep the player is.
+    pub slimedamage: f32,
+
+    /// Whether respawning at a checkpoint restores the velocity
+    /// the player had when reaching it.
+    pub keepcheckpointvel: bool,
+
+    /// How fast players leave teleporters.
+    pub teleportspeed: f32,
+    /// Whether players leave teleporters as fast as they went in, instead of at `teleportspeed`.
+    pub keepteleportspeed: bool,
+
+    /// Per-surface friction and acceleration, the first match wins.
+    /// Slick floors always have no friction and only allow air control.
+    pub surfacemods: Vec<SurfaceMods>,
 }
 impl std::default::Default for MoveSettings {
     fn default() -> MoveSettings {
@@ -40,17 +77,16 @@ impl std::default::Default for MoveSettings {
             friction: 8.0, 
             slidetime: 0.11,
             specialcooldown: 1.0,
+            swimaccel: 4.0,
+            swimfriction: 1.0,
+            swimspeed: 150.0,
+            swimgravity: 60.0,
+            lavadamage: 30.0,
+            slimedamage: 10.0,
+            keepcheckpointvel: false,
+            teleportspeed: 400.0,
+            keepteleportspeed: false,
+            surfacemods: vec![],
         }
     }
 }
-
-pub struct InputSettings {
-    pub sensitivity: f32,
-
-    pub forwardkey: VirtualKeyCode,
-    pub backkey: VirtualKeyCode,
-    pub leftkey: VirtualKeyCode,
-    pub rightkey: VirtualKeyCode,
-    pub jumpkey: VirtualKeyCode,
-}
-
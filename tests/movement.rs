@@ -0,0 +1,659 @@
+//! Movement regression tests.
+//!
+//! Each scenario builds a small map with `MapBuilder`, feeds the player a scripted
+//! sequence of inputs and compares the trajectory against a golden file in
+//! `tests/golden`. When a change to movement is intentional, regenerate the
+//! golden files by running the tests with `VEL0CITY_BLESS=1` and check them in.
+//! A missing golden file is a failure, so new scenarios need blessing too.
+
+extern crate vel0city;
+extern crate nalgebra as na;
+
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::PathBuf;
+use vel0city::Game;
+use vel0city::demo::TickInput;
+use vel0city::map::{self, bsp, Entity, EntityKind, CONTENTS_LAVA, CONTENTS_WATER, SURF_NODAMAGE, SURF_SLICK};
+use vel0city::player::{PlayerEvent, WaterLevel, PLAYER_MUST_DIE, PLAYER_TELEPORTED};
+use vel0city::run::RunState;
+use vel0city::settings::{MoveSettings, SurfaceMods};
+use vel0city::map::builder::MapBuilder;
+use vel0city::map::mover::MoverPath;
+
+/// How far a golden trajectory may drift before the test fails.
+const POS_TOLERANCE: f32 = 0.01;
+const VEL_TOLERANCE: f32 = 0.05;
+
+fn plane(norm: na::Vec3<f32>, dist: f32) -> bsp::Plane {
+    bsp::Plane {
+        norm: norm,
+        dist: dist
+    }
+}
+
+/// A map with a big floor, its top at y = 0. Remember that -y is up.
+fn floor() -> MapBuilder {
+    MapBuilder::new()
+        .add_box(na::Vec3::new(-2048.0, 0.0, -2048.0), na::Vec3::new(2048.0, 64.0, 2048.0))
+}
+
+/// Where a player standing on the floor starts out.
+fn on_floor(x: f32, z: f32) -> na::Pnt3<f32> {
+    na::Pnt3::new(x, -vel0city::player::PLAYER_HALFEXTENTS.y - 0.5, z)
+}
+
+#[derive(Debug)]
+struct Sample {
+    pos: na::Pnt3<f32>,
+    vel: na::Vec3<f32>,
+}
+
+fn run<F: Fn(u32) -> TickInput>(map: map::Map, ticks: u32, script: F) -> (Game, Vec<Sample>) {
+    let mut game = Game::new(map, Default::default());
+    game.add_player();
+    let mut trajectory = vec![];
+    for tick in 0..ticks {
+        game.step(&[script(tick).to_moveinput()]);
+        let pl = &game.players[0];
+        trajectory.push(Sample {
+            pos: pl.pos,
+            vel: pl.vel
+        });
+    }
+    (game, trajectory)
+}
+
+fn format_trajectory(trajectory: &[Sample]) -> String {
+    let mut out = String::new();
+    for s in trajectory {
+        out.push_str(&format!("{} {} {} {} {} {}\n",
+                              s.pos.x, s.pos.y, s.pos.z,
+                              s.vel.x, s.vel.y, s.vel.z));
+    }
+    out
+}
+
+fn parse_trajectory(text: &str) -> Vec<Sample> {
+    text.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+        let n: Vec<f32> = line.split(' ').map(|x| x.trim().parse().unwrap()).collect();
+        assert_eq!(n.len(), 6);
+        Sample {
+            pos: na::Pnt3::new(n[0], n[1], n[2]),
+            vel: na::Vec3::new(n[3], n[4], n[5]),
+        }
+    }).collect()
+}
+
+fn check_golden(name: &str, trajectory: &[Sample]) {
+    let mut path = PathBuf::from("tests/golden");
+    path.push(&format!("{}.txt", name));
+
+    if std::env::var("VEL0CITY_BLESS").is_ok() {
+        let mut f = File::create(&path).unwrap();
+        f.write_all(format_trajectory(trajectory).as_bytes()).unwrap();
+        println!("Wrote golden trajectory {}", path.display());
+        return;
+    }
+
+    let mut expected = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut expected)) {
+        panic!("{}: couldn't read golden trajectory {} ({}). Run with VEL0CITY_BLESS=1 to create it.",
+               name, path.display(), e);
+    }
+
+    let expected = parse_trajectory(&expected);
+    assert_eq!(expected.len(), trajectory.len());
+    for (tick, (e, a)) in expected.iter().zip(trajectory.iter()).enumerate() {
+        let pos_err = na::norm(&(e.pos.to_vec() - a.pos.to_vec()));
+        let vel_err = na::norm(&(e.vel - a.vel));
+        if pos_err > POS_TOLERANCE || vel_err > VEL_TOLERANCE {
+            panic!("{}: trajectory diverged on tick {}: expected {:?}, got {:?}", name, tick, e, a);
+        }
+    }
+}
+
+fn horiz_speed(vel: &na::Vec3<f32>) -> f32 {
+    na::norm(&na::Vec2::new(vel.x, vel.z))
+}
+
+#[test]
+fn strafe_jump() {
+    let map = floor().add_spawnpoint(on_floor(0.0, 1024.0), 0.0).build();
+    // Hold forward and left, turn left steadily and hop every half second.
+    let (game, trajectory) = run(map, 360, |tick| TickInput {
+        wishvel: na::Vec3::new(220.0, 0.0, -220.0),
+        pitch: 0.0,
+        yaw: tick as f32 * 0.004,
+        jump: tick % 60 < 5,
+        special: false,
+    });
+
+    let pl = &game.players[0];
+    assert!(horiz_speed(&pl.vel) > 0.0);
+    // Never fell through the floor.
+    assert!(trajectory.iter().all(|s| s.pos.y < 0.0));
+    check_golden("strafe_jump", &trajectory);
+}
+
+#[test]
+fn step_up() {
+    let map = floor()
+        .add_box(na::Vec3::new(64.0, -2.0, -2048.0), na::Vec3::new(2048.0, 0.0, 2048.0))
+        .add_spawnpoint(on_floor(0.0, 0.0), 0.0)
+        .build();
+    // Walk towards +x, onto the step.
+    let (game, trajectory) = run(map, 120, |_| TickInput {
+        wishvel: na::Vec3::new(0.0, 0.0, -220.0),
+        pitch: 0.0,
+        yaw: -std::f32::consts::FRAC_PI_2,
+        jump: false,
+        special: false,
+    });
+
+    let pl = &game.players[0];
+    assert!(pl.pos.x > 64.0 + pl.halfextents.x);
+    assert!(pl.pos.y < -2.0 - pl.halfextents.y + 0.5);
+    check_golden("step_up", &trajectory);
+}
+
+#[test]
+fn slope_slide() {
+    // Too steep to stand on, going down towards +x.
+    let angle = 55.0 * std::f32::consts::PI / 180.0;
+    let map = MapBuilder::new()
+        .add_brush(vec![
+            plane(na::Vec3::new(angle.sin(), -angle.cos(), 0.0), 0.0),
+            plane(na::Vec3::new(0.0, 1.0, 0.0), 2048.0),
+            plane(na::Vec3::new(1.0, 0.0, 0.0), 1024.0),
+            plane(na::Vec3::new(-1.0, 0.0, 0.0), 1024.0),
+            plane(na::Vec3::new(0.0, 0.0, 1.0), 1024.0),
+            plane(na::Vec3::new(0.0, 0.0, -1.0), 1024.0),
+        ])
+        .add_spawnpoint(na::Pnt3::new(0.0, -64.0, 0.0), 0.0)
+        .build();
+    let (game, trajectory) = run(map, 120, |_| TickInput {
+        wishvel: na::zero(),
+        pitch: 0.0,
+        yaw: 0.0,
+        jump: false,
+        special: false,
+    });
+
+    let pl = &game.players[0];
+    assert!(pl.pos.x > 16.0);
+    assert!(pl.pos.y > 0.0);
+    check_golden("slope_slide", &trajectory);
+}
+
+#[test]
+fn slick_slide() {
+    // Normal floor behind x = 32, slick floor beyond it.
+    let map = MapBuilder::new()
+        .add_box(na::Vec3::new(-2048.0, 0.0, -2048.0), na::Vec3::new(32.0, 64.0, 2048.0))
+        .add_box_with_surface(na::Vec3::new(32.0, 0.0, -2048.0), na::Vec3::new(2048.0, 64.0, 2048.0), SURF_SLICK)
+        .add_spawnpoint(on_floor(-64.0, 0.0), 0.0)
+        .build();
+    // Run towards +x onto the slick floor, then let go.
+    let (game, trajectory) = run(map, 240, |tick| TickInput {
+        wishvel: if tick < 90 { na::Vec3::new(0.0, 0.0, -220.0) } else { na::zero() },
+        pitch: 0.0,
+        yaw: -std::f32::consts::FRAC_PI_2,
+        jump: false,
+        special: false,
+    });
+
+    // Without friction, the player keeps sliding.
+    let pl = &game.players[0];
+    assert!(horiz_speed(&pl.vel) > 150.0);
+    assert!(trajectory.iter().all(|s| s.pos.y < 0.0));
+    check_golden("slick_slide", &trajectory);
+}
+
+/// The player's speed after standing on a SURF_NODAMAGE floor, being given `initial`
+/// speed towards +x and then spending 12 ticks running that way or standing still.
+fn speed_on_surface(surfacemods: Vec<SurfaceMods>, initial: f32, running: bool) -> f32 {
+    let map = MapBuilder::new()
+        .add_box_with_surface(na::Vec3::new(-2048.0, 0.0, -2048.0), na::Vec3::new(2048.0, 64.0, 2048.0), SURF_NODAMAGE)
+        .add_spawnpoint(on_floor(0.0, 0.0), -std::f32::consts::FRAC_PI_2)
+        .build();
+    let mut game = Game::new(map, MoveSettings { surfacemods: surfacemods, ..Default::default() });
+    game.add_player();
+    // Land, and get past the slide after landing.
+    for _ in 0..60 {
+        game.step(&[stand_still(0).to_moveinput()]);
+    }
+
+    game.players[0].vel = na::Vec3::new(initial, 0.0, 0.0);
+    for tick in 0..12 {
+        let input = if running { run_forward() } else { stand_still(tick) };
+        game.step(&[input.to_moveinput()]);
+    }
+    horiz_speed(&game.players[0].vel)
+}
+
+#[test]
+fn surface_mods() {
+    // Half the acceleration: slower to get up to speed...
+    let mods = vec![SurfaceMods { flags: SURF_NODAMAGE, friction: 1.0, accel: 0.5 }];
+    let normal = speed_on_surface(vec![], 0.0, true);
+    let modded = speed_on_surface(mods, 0.0, true);
+    assert!(modded < normal * 0.9, "{} isn't much slower than {}", modded, normal);
+
+    // ...and twice the friction: quicker to stop.
+    let mods = vec![SurfaceMods { flags: SURF_NODAMAGE, friction: 2.0, accel: 1.0 }];
+    let normal = speed_on_surface(vec![], 300.0, false);
+    let modded = speed_on_surface(mods, 300.0, false);
+    assert!(modded < normal * 0.9, "{} isn't much slower than {}", modded, normal);
+
+    // Mods for other flags leave the floor alone.
+    let mods = vec![SurfaceMods { flags: SURF_SLICK, friction: 2.0, accel: 1.0 }];
+    assert_eq!(speed_on_surface(mods, 300.0, false), normal);
+}
+
+#[test]
+fn swim_up() {
+    // A pool 128 units deep, sunk into the floor.
+    let map = MapBuilder::new()
+        .add_box(na::Vec3::new(-2048.0, 128.0, -2048.0), na::Vec3::new(2048.0, 192.0, 2048.0))
+        .add_box_with_contents(na::Vec3::new(-2048.0, 0.0, -2048.0), na::Vec3::new(2048.0, 128.0, 2048.0), CONTENTS_WATER)
+        .add_spawnpoint(na::Pnt3::new(0.0, 100.0, 0.0), 0.0)
+        .build();
+    // Sink for a bit, then swim forwards and up.
+    let (game, trajectory) = run(map, 240, |tick| TickInput {
+        wishvel: if tick < 60 { na::zero() } else { na::Vec3::new(0.0, 0.0, -150.0) },
+        pitch: 0.0,
+        yaw: 0.0,
+        jump: tick >= 60,
+        special: false,
+    });
+
+    // Slowly sinking, rather than falling.
+    assert!(trajectory[59].vel.y > 0.0 && trajectory[59].vel.y < 100.0);
+    let pl = &game.players[0];
+    assert!(pl.pos.y < trajectory[59].pos.y);
+    assert!(pl.pos.z < -64.0);
+    assert!(pl.waterlevel >= WaterLevel::Feet);
+    check_golden("swim_up", &trajectory);
+}
+
+#[test]
+fn lava_kills() {
+    let map = floor()
+        .add_box_with_contents(na::Vec3::new(-64.0, -64.0, -64.0), na::Vec3::new(64.0, 0.0, 64.0), CONTENTS_LAVA)
+        .add_spawnpoint(on_floor(0.0, 0.0), 0.0)
+        .build();
+    let mut game = Game::new(map, Default::default());
+    game.add_player();
+    let mut health = vec![];
+    let mut respawned = false;
+    for _ in 0..240 {
+        game.step(&[TickInput {
+            wishvel: na::zero(),
+            pitch: 0.0,
+            yaw: 0.0,
+            jump: false,
+            special: false,
+        }.to_moveinput()]);
+        let pl = &game.players[0];
+        assert_eq!(pl.waterlevel, WaterLevel::Eyes);
+        health.push(pl.health);
+        respawned |= pl.flags.contains(PLAYER_TELEPORTED);
+    }
+
+    assert!(health[1] < health[0]);
+    // Fully submerged, 30 damage per second three times over kills in just over a second.
+    assert!(respawned);
+}
+
+#[test]
+fn jump_pad() {
+    let apex = na::Pnt3::new(256.0, -256.0, 0.0);
+    let map = floor()
+        .add_brush_entity(Entity::new("trigger_push", EntityKind::JumpPad { target: apex }),
+                          na::Vec3::new(-16.0, -8.0, -16.0), na::Vec3::new(16.0, 0.0, 16.0))
+        .add_spawnpoint(on_floor(0.0, 0.0), 0.0)
+        .build();
+    let (_, trajectory) = run(map, 180, |_| TickInput {
+        wishvel: na::zero(),
+        pitch: 0.0,
+        yaw: 0.0,
+        jump: false,
+        special: false,
+    });
+
+    // The top of the arc is where the pad is aimed.
+    let top = trajectory.iter().fold(&trajectory[0], |top, s| if s.pos.y < top.pos.y { s } else { top });
+    assert!(na::approx_eq_eps(&top.pos.y, &apex.y, &4.0));
+    assert!(na::approx_eq_eps(&top.pos.x, &apex.x, &8.0));
+    check_golden("jump_pad", &trajectory);
+}
+
+#[test]
+fn teleporter() {
+    let dest = na::Pnt3::new(1024.0, -64.0, 0.0);
+    // Facing +x.
+    let yaw = -std::f32::consts::FRAC_PI_2;
+    let map = floor()
+        .add_brush_entity(Entity::new("trigger_teleport", EntityKind::Teleporter { dest: dest, yaw: yaw }),
+                          na::Vec3::new(-16.0, -32.0, -80.0), na::Vec3::new(16.0, 0.0, -64.0))
+        .add_spawnpoint(on_floor(0.0, 0.0), 0.0)
+        .build();
+    // Walk forwards, into the teleporter.
+    let mut game = Game::new(map, Default::default());
+    game.add_player();
+    let mut teleported = None;
+    for tick in 0..120 {
+        game.step(&[TickInput {
+            wishvel: na::Vec3::new(0.0, 0.0, -220.0),
+            pitch: 0.0,
+            yaw: 0.0,
+            jump: false,
+            special: false,
+        }.to_moveinput()]);
+        let pl = &game.players[0];
+        if pl.flags.contains(PLAYER_TELEPORTED) {
+            assert_eq!(pl.pos, dest);
+            // Leaving the teleporter the way the destination faces.
+            assert!(pl.vel.x > 0.0);
+            assert!(na::approx_eq_eps(&pl.vel.z, &0.0, &0.01));
+            teleported = Some(tick);
+            break;
+        }
+    }
+    assert!(teleported.is_some());
+}
+
+/// A 64 unit wide platform sitting on the floor, that goes up 128 units and back down.
+fn elevator() -> MapBuilder {
+    floor()
+        .add_mover(na::Vec3::new(-32.0, -8.0, -32.0), na::Vec3::new(32.0, 0.0, 32.0), MoverPath {
+            stops: vec![na::zero(), na::Vec3::new(0.0, -128.0, 0.0)],
+            speed: 64.0,
+            wait: 0.5,
+            triggered: false
+        })
+        .add_spawnpoint(na::Pnt3::new(0.0, -8.0 - vel0city::player::PLAYER_HALFEXTENTS.y - 0.5, 0.0), 0.0)
+}
+
+fn stand_still(_: u32) -> TickInput {
+    TickInput {
+        wishvel: na::zero(),
+        pitch: 0.0,
+        yaw: 0.0,
+        jump: false,
+        special: false,
+    }
+}
+
+#[test]
+fn ride_elevator() {
+    let (game, trajectory) = run(elevator().build(), 360, stand_still);
+
+    // Waiting at the top, 128 units up.
+    let top = &trajectory[329];
+    assert!(na::approx_eq_eps(&top.pos.y, &(-8.0 - 128.0 - game.players[0].halfextents.y), &1.0));
+    assert!(trajectory.iter().all(|s| s.pos.x == 0.0 && s.pos.z == 0.0));
+    check_golden("ride_elevator", &trajectory);
+}
+
+#[test]
+fn elevator_crushes() {
+    let map = elevator()
+        .add_box(na::Vec3::new(-2048.0, -128.0, -2048.0), na::Vec3::new(2048.0, -64.0, 2048.0))
+        .build();
+    let mut game = Game::new(map, Default::default());
+    game.add_player();
+    let mut crushed = false;
+    for tick in 0..240 {
+        game.step(&[stand_still(tick).to_moveinput()]);
+        crushed |= game.players[0].flags.contains(PLAYER_TELEPORTED);
+    }
+    assert!(crushed);
+}
+
+#[test]
+fn spawn_inside_floor() {
+    // Spawning halfway into the floor shouldn't leave the player stuck there.
+    let map = floor().add_spawnpoint(na::Pnt3::new(0.0, 0.0, 0.0), 0.0).build();
+    let (game, trajectory) = run(map, 60, |_| TickInput {
+        wishvel: na::Vec3::new(0.0, 0.0, -220.0),
+        pitch: 0.0,
+        yaw: 0.0,
+        jump: false,
+        special: false,
+    });
+
+    let pl = &game.players[0];
+    assert!(trajectory.iter().all(|s| s.pos.y <= -pl.halfextents.y));
+    assert!(pl.pos.z < -64.0);
+}
+
+#[test]
+fn grapple_swing() {
+    let map = floor()
+        .add_box(na::Vec3::new(-2048.0, -576.0, -2048.0), na::Vec3::new(2048.0, -512.0, 2048.0))
+        .add_spawnpoint(on_floor(0.0, 1024.0), 0.0)
+        .build();
+    // Run forward, grapple the ceiling ahead and reel in.
+    let (game, trajectory) = run(map, 240, |tick| TickInput {
+        wishvel: na::Vec3::new(0.0, 0.0, -220.0),
+        pitch: -0.8,
+        yaw: 0.0,
+        jump: tick >= 60,
+        special: tick >= 30,
+    });
+
+    let pl = &game.players[0];
+    assert!(pl.grapple.is_some());
+    assert!(pl.pos.z < 1024.0);
+    assert!(trajectory.iter().any(|s| s.pos.y < -64.0));
+    check_golden("grapple_swing", &trajectory);
+}
+
+/// A checkpoint across the floor from x = 64 to 96, with the player spawning behind it facing +x.
+fn checkpoint_course() -> map::Map {
+    floor()
+        .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Checkpoint),
+                          na::Vec3::new(64.0, -64.0, -2048.0), na::Vec3::new(96.0, 0.0, 2048.0))
+        .add_spawnpoint(on_floor(0.0, 0.0), -std::f32::consts::FRAC_PI_2)
+        .build()
+}
+
+fn run_forward() -> TickInput {
+    TickInput {
+        wishvel: na::Vec3::new(0.0, 0.0, -220.0),
+        pitch: 0.0,
+        yaw: -std::f32::consts::FRAC_PI_2,
+        jump: false,
+        special: false,
+    }
+}
+
+/// Runs forward until the player reaches a checkpoint, returning the event it got for it.
+fn reach_checkpoint(game: &mut Game) -> PlayerEvent {
+    for _ in 0..240 {
+        game.step(&[run_forward().to_moveinput()]);
+        if let Some(event) = game.players[0].events.first() {
+            return event.clone();
+        }
+    }
+    panic!("Never reached the checkpoint");
+}
+
+/// Runs through the checkpoint and a bit further, then kills the player. Returns the
+/// player's velocity at the checkpoint.
+fn die_after_checkpoint(game: &mut Game) -> na::Vec3<f32> {
+    game.add_player();
+    reach_checkpoint(game);
+    for _ in 0..60 {
+        game.step(&[run_forward().to_moveinput()]);
+    }
+    assert!(game.players[0].pos.x > 96.0);
+
+    let vel = game.players[0].checkpoint.as_ref().unwrap().vel;
+    game.players[0].flags.insert(PLAYER_MUST_DIE);
+    game.step(&[stand_still(0).to_moveinput()]);
+    assert!(game.players[0].flags.contains(PLAYER_TELEPORTED));
+    vel
+}
+
+#[test]
+fn touch_checkpoint() {
+    let mut game = Game::new(checkpoint_course(), Default::default());
+    game.add_player();
+
+    // Not on a run, so there's no split time.
+    assert_eq!(reach_checkpoint(&mut game), PlayerEvent::Checkpoint { entity: 1, split: None });
+    {
+        let checkpoint = game.players[0].checkpoint.as_ref().unwrap();
+        assert_eq!(checkpoint.entity, 1);
+        assert!(checkpoint.pos.x > 56.0 && checkpoint.pos.x < 104.0);
+        assert!(checkpoint.vel.x > 0.0);
+    }
+
+    // Staying in it, or touching it again, doesn't count as reaching it again.
+    for _ in 0..60 {
+        game.step(&[run_forward().to_moveinput()]);
+        assert!(game.players[0].events.is_empty());
+    }
+}
+
+#[test]
+fn respawn_at_checkpoint() {
+    let mut game = Game::new(checkpoint_course(), Default::default());
+    die_after_checkpoint(&mut game);
+
+    let pl = &game.players[0];
+    let checkpoint = pl.checkpoint.as_ref().unwrap();
+    assert!(na::approx_eq_eps(&pl.pos, &checkpoint.pos, &1.0));
+    let forward = na::Vec3::new(0.0, 0.0, -1.0);
+    assert!(na::approx_eq_eps(&na::rotate(&pl.eyeang, &forward), &na::rotate(&checkpoint.eyeang, &forward), &0.01));
+    // Starting over from a standstill.
+    assert!(horiz_speed(&pl.vel) < 1.0);
+}
+
+#[test]
+fn keep_checkpoint_vel() {
+    let settings = MoveSettings {
+        keepcheckpointvel: true,
+        ..Default::default()
+    };
+    let mut game = Game::new(checkpoint_course(), settings);
+    let vel = die_after_checkpoint(&mut game);
+
+    // Friction has had one tick to slow it down.
+    let pl = &game.players[0];
+    assert!(na::approx_eq_eps(&pl.pos, &pl.checkpoint.as_ref().unwrap().pos, &4.0));
+    assert!(pl.vel.x > 0.0);
+    assert!(horiz_speed(&pl.vel) > horiz_speed(&vel) * 0.5);
+}
+
+/// A start line, a checkpoint and a goal one after the other along +x, with the player
+/// spawning behind the start line facing them.
+fn run_course() -> map::Map {
+    let across = |x: f32| (na::Vec3::new(x, -64.0, -2048.0), na::Vec3::new(x + 32.0, 0.0, 2048.0));
+    let (start, checkpoint, goal) = (across(32.0), across(128.0), across(256.0));
+    floor()
+        .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Start), start.0, start.1)
+        .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Checkpoint), checkpoint.0, checkpoint.1)
+        .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Goal), goal.0, goal.1)
+        .add_spawnpoint(on_floor(0.0, 0.0), -std::f32::consts::FRAC_PI_2)
+        .build()
+}
+
+/// Runs forward until `done` says to stop.
+fn run_until<F: Fn(&Game) -> bool>(game: &mut Game, done: F) {
+    for _ in 0..600 {
+        game.step(&[run_forward().to_moveinput()]);
+        if done(game) {
+            return;
+        }
+    }
+    panic!("Ran out of time");
+}
+
+fn kill(game: &mut Game) {
+    game.players[0].flags.insert(PLAYER_MUST_DIE);
+    game.step(&[stand_still(0).to_moveinput()]);
+}
+
+#[test]
+fn timed_run() {
+    let mut game = Game::new(run_course(), Default::default());
+    game.add_player();
+    assert_eq!(game.players[0].run, RunState::NotStarted);
+
+    let mut left_start = None;
+    let mut split = None;
+    let mut result = None;
+    for _ in 0..360 {
+        game.step(&[run_forward().to_moveinput()]);
+        let pl = &game.players[0];
+        for event in &pl.events {
+            match *event {
+                PlayerEvent::Checkpoint { entity, split: s } => {
+                    assert_eq!(entity, 2);
+                    assert!(split.is_none() && s.is_some());
+                    split = s;
+                },
+                PlayerEvent::Finished(ref r) => {
+                    assert!(result.is_none());
+                    result = Some((game.tick, r.clone()));
+                }
+            }
+        }
+        match pl.run {
+            RunState::NotStarted => assert!(left_start.is_none()),
+            RunState::Running { start, ticks, ref splits } => {
+                // The clock stays at zero until the player leaves the start line.
+                if ticks == 0 {
+                    left_start = Some(game.tick);
+                }
+                assert_eq!(Some(start), left_start);
+                assert_eq!(ticks as u64, game.tick - start);
+                assert_eq!(splits.len(), if split.is_some() { 1 } else { 0 });
+            },
+            RunState::Finished(ref r) => assert_eq!(Some(r), result.as_ref().map(|&(_, ref r)| r)),
+        }
+    }
+
+    let (finished, result) = result.expect("Never finished");
+    let left_start = left_start.unwrap();
+    assert_eq!(result.ticks as u64, finished - left_start);
+    assert_eq!(result.splits, vec![split.unwrap()]);
+    assert!(split.unwrap() < result.ticks);
+    assert_eq!(result.tick, vel0city::TICK);
+    assert_eq!(game.players[0].run, RunState::Finished(result));
+}
+
+#[test]
+fn respawning_restarts_run() {
+    let mut game = Game::new(run_course(), Default::default());
+    game.add_player();
+
+    // Dying before the checkpoint means starting over.
+    run_until(&mut game, |game| match game.players[0].run {
+        RunState::Running { ticks, .. } => ticks > 10,
+        _ => false
+    });
+    assert!(game.players[0].pos.x < 128.0);
+    kill(&mut game);
+    assert_eq!(game.players[0].run, RunState::NotStarted);
+
+    // Dying after it goes back to the checkpoint, with the clock still running.
+    run_until(&mut game, |game| game.players[0].checkpoint.is_some());
+    let start = match game.players[0].run {
+        RunState::Running { start, .. } => start,
+        ref run => panic!("Expected a run, got {:?}", run)
+    };
+    kill(&mut game);
+    match game.players[0].run {
+        RunState::Running { start: s, ticks, ref splits } => {
+            assert_eq!(s, start);
+            assert_eq!(ticks as u64, game.tick - start);
+            assert_eq!(splits.len(), 1);
+        },
+        ref run => panic!("Expected a run, got {:?}", run)
+    }
+}
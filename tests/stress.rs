@@ -0,0 +1,95 @@
+//! Stress tests for collision at high speeds.
+//!
+//! Players get fired at thin walls and into acute corners at up to
+//! `MoveSettings::maxspeed`, and must never end up inside anything solid.
+
+extern crate vel0city;
+extern crate nalgebra as na;
+
+use vel0city::Game;
+use vel0city::demo::TickInput;
+use vel0city::map::{self, bsp, MASK_PLAYERSOLID};
+use vel0city::map::builder::MapBuilder;
+
+fn plane(norm: na::Vec3<f32>, dist: f32) -> bsp::Plane {
+    bsp::Plane {
+        norm: norm,
+        dist: dist
+    }
+}
+
+fn speeds() -> Vec<f32> {
+    let maxspeed = vel0city::settings::MoveSettings::default().maxspeed;
+    vec![500.0, 1000.0, 2000.0, 4000.0, maxspeed]
+}
+
+/// Fires a player from the origin with the given velocity, checking that it's never
+/// inside anything solid. Returns where it ended up.
+fn fire(map: map::Map, vel: na::Vec3<f32>, ticks: u32) -> na::Pnt3<f32> {
+    let mut game = Game::new(map, Default::default());
+    game.add_player();
+    game.players[0].vel = vel;
+    for tick in 0..ticks {
+        game.step(&[TickInput {
+            wishvel: na::zero(),
+            pitch: 0.0,
+            yaw: 0.0,
+            jump: false,
+            special: false,
+        }.to_moveinput()]);
+        let pl = &game.players[0];
+        let contents = game.map.box_contents(&pl.pos, &pl.halfextents).contents;
+        if contents.intersects(MASK_PLAYERSOLID) {
+            panic!("Fired at {:?}, stuck in {:?} at {:?} on tick {}", vel, contents, pl.pos, tick);
+        }
+    }
+    game.players[0].pos
+}
+
+#[test]
+fn thin_wall() {
+    for &speed in speeds().iter() {
+        for &angle in [0.0f32, 0.3, 0.8, 1.2].iter() {
+            // A wall only two units thick, at x = 64.
+            let map = MapBuilder::new()
+                .add_box(na::Vec3::new(64.0, -1024.0, -1024.0), na::Vec3::new(66.0, 1024.0, 1024.0))
+                .add_spawnpoint(na::Pnt3::new(0.0, 0.0, 0.0), 0.0)
+                .build();
+            let vel = na::Vec3::new(angle.cos(), 0.0, angle.sin()) * speed;
+            let pos = fire(map, vel, 30);
+            assert!(pos.x < 64.0, "Went through the wall at {:?}: {:?}", vel, pos);
+        }
+    }
+}
+
+#[test]
+fn acute_corner() {
+    // Two walls meeting at x = 256, 30 degrees apart, opening towards -x.
+    let half = 15.0 * std::f32::consts::PI / 180.0;
+    let (sin, cos) = (half.sin(), half.cos());
+    let map = || MapBuilder::new()
+        .add_brush(vec![
+            plane(na::Vec3::new(-sin, 0.0, -cos), -256.0 * sin),
+            plane(na::Vec3::new(1.0, 0.0, 0.0), 512.0),
+            plane(na::Vec3::new(0.0, 0.0, 1.0), 512.0),
+            plane(na::Vec3::new(0.0, 1.0, 0.0), 1024.0),
+            plane(na::Vec3::new(0.0, -1.0, 0.0), 1024.0),
+        ])
+        .add_brush(vec![
+            plane(na::Vec3::new(-sin, 0.0, cos), -256.0 * sin),
+            plane(na::Vec3::new(1.0, 0.0, 0.0), 512.0),
+            plane(na::Vec3::new(0.0, 0.0, -1.0), 512.0),
+            plane(na::Vec3::new(0.0, 1.0, 0.0), 1024.0),
+            plane(na::Vec3::new(0.0, -1.0, 0.0), 1024.0),
+        ])
+        .add_spawnpoint(na::Pnt3::new(0.0, 0.0, 0.0), 0.0)
+        .build();
+
+    for &speed in speeds().iter() {
+        for &angle in [0.0f32, 0.1, -0.2, 0.25].iter() {
+            let vel = na::Vec3::new(angle.cos(), 0.0, angle.sin()) * speed;
+            let pos = fire(map(), vel, 60);
+            assert!(pos.x < 256.0, "Went through the corner at {:?}: {:?}", vel, pos);
+        }
+    }
+}
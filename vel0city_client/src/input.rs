@@ -1,10 +1,12 @@
 use na::{
     self,
+    Rotate,
     Rotation
 };
 use glutin;
 use settings::InputSettings;
-use player::movement::MoveInput;
+use vel0city::demo::TickInput;
+use vel0city::settings::MoveSettings;
 use std::f32::consts::{
     PI_2,
     FRAC_PI_2
@@ -126,12 +128,21 @@ impl Input {
         }
     }
 
+    /// Turns the view to face the same heading as `ang`, levelling the pitch.
+    pub fn reset_ang(&mut self, ang: &na::UnitQuat<f32>) {
+        let forward = ang.rotate(&na::Vec3::new(0.0, 0.0, -1.0));
+        self.yaw = wrap_yaw(f32::atan2(-forward.x, -forward.z));
+        self.pitch = 0.0;
+    }
+
     pub fn get_ang(&self) -> na::UnitQuat<f32> {
         na::UnitQuat::new(na::Vec3::new(0.0, self.yaw, 0.0))
             .append_rotation(&na::Vec3::new(self.pitch, 0.0, 0.0))
     }
 
-    pub fn make_moveinput(&self, movesettings: &::settings::MoveSettings) -> MoveInput {
+    /// Samples the current input state. Turn it into a `MoveInput` with
+    /// `TickInput::to_moveinput`, so recorded demos replay exactly.
+    pub fn make_tickinput(&self, movesettings: &MoveSettings) -> TickInput {
         let mut wvel: na::Vec3<f32> = na::zero();
         if self.buttons.contains(BUTTON_FORWARD) {
             wvel.z -= movesettings.movespeed;
@@ -148,9 +159,10 @@ impl Input {
         let jump = self.buttons.contains(BUTTON_JUMP);
         let special = self.buttons.contains(BUTTON_SPECIAL);
 
-        MoveInput {
+        TickInput {
             wishvel: wvel,
-            eyeang: self.get_ang(),
+            pitch: self.pitch,
+            yaw: self.yaw,
             jump: jump,
             special: special,
         }
@@ -0,0 +1,353 @@
+extern crate glium;
+extern crate glutin;
+extern crate vel0city;
+extern crate vel0city_base;
+extern crate vel0city_graphics;
+extern crate nalgebra as na;
+#[macro_use]
+extern crate bitflags;
+extern crate clock_ticks;
+extern crate image;
+extern crate rustc_serialize;
+
+use std::borrow::ToOwned;
+use glium::DisplayBuild;
+use glium::Surface;
+
+use vel0city_base::assets;
+use vel0city_graphics::hud;
+use na::{
+    Diag,
+    Rotation,
+    Rotate,
+    ToHomogeneous,
+    Inv
+};
+use std::f32::consts::{
+    PI,
+};
+
+pub mod input;
+pub mod settings;
+
+pub struct Client {
+    input: input::Input,
+    hudmanager: vel0city_graphics::hud::HudManager,
+    hudelements: Vec<hud::Element>,
+    scene: Option<vel0city_graphics::Scene>,
+}
+impl Client {
+    fn new(display: &glium::Display) -> Client {
+        let input = input::Input::new();
+        let hudmanager = hud::HudManager::new(display);
+
+        let tex = assets::load_bin_asset("textures/arrow.png").unwrap();
+        let tex = image::load(std::io::Cursor::new(tex), image::PNG).unwrap();
+        let tex = glium::Texture2d::new(display, tex);
+
+        fn id(context: &hud::Context) -> Option<na::Mat4<f32>> {
+            let eyedir = context.eyeang.rotate(&na::Vec3::new(0.0, 0.0, -1.0));
+            let eye_heading = f32::atan2(eyedir.x, eyedir.z);
+            let vel_heading = f32::atan2(context.player_vel.x, context.player_vel.z);
+            let ang = eye_heading - vel_heading; 
+            let scale = na::norm(&na::Vec2::new(context.player_vel.x, context.player_vel.z)) / 1500.0;
+            if scale > 0.03 {
+                let scalemat = na::Mat4::from_diag(&na::Vec4::new(0.15, scale, 1.0, 1.0));
+                let rotmat = na::Rot3::new(na::Vec3::new(0.0, 0.0, ang)).to_homogeneous();
+                Some(rotmat * scalemat)
+            } else {
+                None
+            }
+        }
+
+        Client {
+            input: input,
+            hudmanager: hudmanager,
+            hudelements: vec![hud::Element {
+                transform: na::Iso2::new(na::zero(), na::zero()),
+                element_type: hud::ElementType::TransformedBlit {
+                    texture: tex,
+                    f: id 
+                }
+            }],
+            scene: None,
+        }
+    }
+}
+
+/// How finely curved surfaces get tessellated for drawing.
+const PATCH_LEVEL: u32 = 8;
+
+/// Where the client keeps the files it writes: ~/.vel0city, or .vel0city in
+/// the working directory if there's no home directory.
+fn user_path(name: &str) -> std::path::PathBuf {
+    let mut path = std::env::home_dir().unwrap_or_else(std::path::PathBuf::new);
+    path.push(".vel0city");
+    path.push(name);
+    path
+}
+
+/// Appends a finished run to the file at `path`, one run per line.
+fn save_run(path: &std::path::Path, result: &vel0city::run::RunResult) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if let Some(dir) = path.parent() {
+        try!(std::fs::create_dir_all(dir));
+    }
+    let mut file = try!(std::fs::OpenOptions::new()
+        .write(true)
+        .append(true)
+        .create(true)
+        .open(path));
+    writeln!(file, "{}", rustc_serialize::json::as_json(result))
+}
+
+/// Saves everything played this session to `path`, so it can be watched
+/// again with `vel0city_client <path>`.
+fn save_demo(path: &std::path::Path, demo: &vel0city::demo::Demo) -> Result<(), vel0city::demo::DemoError> {
+    use std::io::Write;
+
+    if let Some(dir) = path.parent() {
+        try!(std::fs::create_dir_all(dir));
+    }
+    let file = try!(std::fs::File::create(path));
+    let mut writer = std::io::BufWriter::new(file);
+    try!(demo.write(&mut writer));
+    try!(writer.flush());
+    Ok(())
+}
+
+fn load_demo(path: &std::path::Path) -> Result<vel0city::demo::Demo, vel0city::demo::DemoError> {
+    let file = try!(std::fs::File::open(path));
+    vel0city::demo::Demo::read(&mut std::io::BufReader::new(file))
+}
+
+/// `vel0city_client [--record <demo>] [<demo to play back>]`
+struct Options {
+    /// A demo to play back before handing control to the player.
+    playback: Option<std::path::PathBuf>,
+    /// Where to save the demo of this session. Defaults to demo.dem next to the saved runs.
+    record: std::path::PathBuf,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        playback: None,
+        record: user_path("demo.dem"),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--record" {
+            options.record = std::path::PathBuf::from(args.next().expect("--record needs a path"));
+        } else {
+            options.playback = Some(std::path::PathBuf::from(arg));
+        }
+    }
+    options
+}
+
+#[cfg(not(test))]
+fn main() {
+    let options = parse_args();
+    let playback = options.playback.as_ref().map(|path| {
+        load_demo(path).unwrap_or_else(|e| panic!("Couldn't load demo {}: {:?}", path.display(), e))
+    });
+
+    let display = glutin::WindowBuilder::new()
+        // .with_vsync()
+        .with_title("vel0city".to_owned())
+        .with_dimensions(800, 600)
+        .build_glium()
+        .unwrap();
+    let mut client = Client::new(&display);
+    let (x, y) = display.get_framebuffer_dimensions();
+
+    let proj = na::Persp3::new(x as f32 / y as f32, 90.0, 1.0, 4096.0).to_mat();
+
+    let mapname = playback.as_ref().map(|demo| demo.mapname.clone()).unwrap_or("maps/test.bsp".to_owned());
+    let asset = assets::load_bin_asset(&mapname).unwrap();
+    let movesettings = match playback {
+        Some(ref demo) => {
+            if !demo.matches_map(&asset) {
+                println!("Warning: demo was recorded on a different version of {}", mapname);
+            }
+            demo.movesettings.clone()
+        },
+        None => std::default::Default::default()
+    };
+    let mut game = vel0city::Game::new(vel0city::map::q3_import::import(&asset).unwrap(),
+                                       movesettings);
+    game.add_player();
+    let mut recording = vel0city::demo::Demo::new(&mapname, &asset, &game.movesettings);
+    let mut ghostrecorder = vel0city::ghost::GhostRecorder::new();
+    // The fastest run so far this session, to race against.
+    let mut bestghost: Option<vel0city::ghost::Ghost> = None;
+    let ghostrenderer = vel0city_graphics::ghost::GhostRenderer::new(&display, &vel0city::player::PLAYER_HALFEXTENTS);
+    let runs_path = user_path("runs.json");
+    client.input.reset_ang(&game.players[0].eyeang);
+
+    let mapmodel = vel0city_graphics::mapmodel::import_graphics_model(&asset, &display, PATCH_LEVEL).unwrap();
+    if !mapmodel.skipped_faces.is_empty() {
+        println!("Warning: {} has {} faces of unknown types, which won't be drawn", mapname, mapmodel.skipped_faces.len());
+    }
+    client.scene = Some(vel0city_graphics::Scene {
+        map: mapmodel,
+        lights: vec![ vel0city_graphics::Light { position: na::zero(), intensity: 0.0, radius: 0.5, color: na::Vec3::new(0.0, 1.0, 1.0) }] 
+    });
+    
+    let mut winsize;
+    {
+        let window = display.get_window().unwrap();
+        winsize = window.get_inner_size().unwrap();
+        window.set_cursor_state(glutin::CursorState::Grab).unwrap();
+    }
+    //client.input.cursorpos = (winsize.0 as i32 / 2, winsize.1 as i32 / 2);
+
+    let psystem = vel0city_graphics::passes::PassSystem::new(&display);
+    let cel_program = glium::Program::from_source(
+        &display,
+        &assets::load_str_asset("shaders/post/vertex.glsl").unwrap(),
+        &assets::load_str_asset("shaders/post/cel_fragment.glsl").unwrap(),
+        None
+        ).unwrap();
+    let cel_technique = vel0city_graphics::passes::Technique {
+        shader: cel_program,
+        drawparams: glium::DrawParameters {
+            ..::std::default::Default::default()
+        }
+    };
+    let light_program = glium::Program::from_source(
+        &display,
+        &assets::load_str_asset("shaders/light/vertex.glsl").unwrap(),
+        &assets::load_str_asset("shaders/light/dlight_fragment.glsl").unwrap(),
+        None
+        ).unwrap();
+    let light_technique = vel0city_graphics::passes::Technique {
+        shader: light_program,
+        drawparams: glium::DrawParameters {
+            blending_function: Some(glium::BlendingFunction::Addition {
+                source: glium::LinearBlendingFactor::One,
+                destination: glium::LinearBlendingFactor::One,
+            }),
+            ..::std::default::Default::default()
+        }
+    };
+
+    let mut pass_data = vel0city_graphics::passes::PassData::new(&display, (winsize.0, winsize.1)); 
+    
+    let tick = vel0city::TICK as f64;
+    // Values below 1 slow the game down; the simulation itself always runs at one tick per step.
+    let timescale = 1.0;
+    let mut lasttime = clock_ticks::precise_time_s();
+    let mut accumtime = 0.0;
+    let mut smoothtime = 0.0;
+    'mainloop: loop { 
+        let curtime = clock_ticks::precise_time_s();
+        let frametime = curtime - lasttime;
+        accumtime += frametime * timescale;
+        smoothtime = (smoothtime + frametime) / 2.0;
+        lasttime = curtime;
+        //println!("frametime: {}us", smoothtime * 1000.0 * 1000.0);
+
+        let win = display.get_window().unwrap();
+        for ev in win.poll_events() {
+            match &ev {
+                &glutin::Event::Resized(width, height) => {
+                    winsize = (width, height);
+                    if winsize.0 < 2 { winsize.0 = 2; }
+                    if winsize.1 < 2 { winsize.1 = 2; }
+                    pass_data = vel0city_graphics::passes::PassData::new(&display, (winsize.0, winsize.1)); 
+                    client.input.cursorpos = (winsize.0 as i32 / 2, winsize.1 as i32 / 2);
+                },
+                &glutin::Event::Closed => {
+                    if let Err(e) = save_demo(&options.record, &recording) {
+                        println!("Warning: couldn't save demo to {}: {:?}", options.record.display(), e);
+                    }
+                    break 'mainloop
+                },
+                _ => ()
+            }
+
+            client.input.handle_event(&win, &ev);
+        }
+
+
+        if accumtime >= tick {
+            // handle dropped frames more gracefully
+            accumtime = f64::min(tick * 3.0, accumtime);
+            while accumtime >= tick {
+                let played = playback.as_ref().and_then(|demo| demo.inputs.get(game.tick as usize).cloned());
+                let ti = match played {
+                    Some(ti) => ti,
+                    None => client.input.make_tickinput(&game.movesettings)
+                };
+                accumtime -= tick;
+                recording.inputs.push(ti);
+                game.step(&[ti.to_moveinput()]);
+                if let Some(ghost) = ghostrecorder.record(&game.players[0]) {
+                    if bestghost.as_ref().map_or(true, |best| ghost.ticks < best.ticks) {
+                        bestghost = Some(ghost);
+                    }
+                }
+                let playback_over = playback.as_ref().map_or(false, |demo| demo.inputs.len() as u64 == game.tick);
+                if playback_over || game.players[0].flags.contains(vel0city::player::PLAYER_TELEPORTED) {
+                    client.input.reset_ang(&game.players[0].eyeang);
+                }
+                for ev in &game.players[0].events {
+                    if let vel0city::player::PlayerEvent::Finished(ref result) = *ev {
+                        if let Err(e) = save_run(&runs_path, result) {
+                            println!("Warning: couldn't save run to {}: {}", runs_path.display(), e);
+                        }
+                    }
+                }
+            }
+        }
+
+        let pv = game.players[0].vel;
+        let rot = game.players[0].eyeang
+            .append_rotation(&na::Vec3::new(PI, 0.0, 0.0)).to_rot();
+
+        let l = na::Iso3::new_with_rotmat(na::zero(), rot).inv().unwrap().to_homogeneous();
+        let v = na::Iso3::new(game.players[0].get_eyepos().to_vec() * -1.0, na::zero()).to_homogeneous(); 
+        let lv = l * v;
+        //l.inv();
+        let view = vel0city_graphics::View {
+            cam: lv,
+            w2s: proj * lv,
+        };
+
+        let mut target = display.draw();
+        pass_data.get_framebuffer_for_prepass(&display).clear_depth(1.0);
+        if let Some(ref mut scene) = client.scene {
+            scene.lights[0].position = game.players[0].pos.to_vec() + na::Vec3::new(0.0, vel0city::player::PLAYER_HALFEXTENTS.y * 0.1, 0.0);
+            scene.lights[0].intensity = na::clamp(na::norm(&na::Vec2::new(pv.x, pv.z)) / 5.0, 2.0, 50.0);
+
+
+            {
+                let mut prepass = pass_data.get_framebuffer_for_prepass(&display);
+                vel0city_graphics::draw_scene(&display,
+                                               &mut prepass,
+                                               &scene,
+                                               &view);
+                if let (Some(ghost), &vel0city::run::RunState::Running { ticks, .. }) = (bestghost.as_ref(), &game.players[0].run) {
+                    // Keep the ghost in step with the live timer, between ticks too.
+                    if let Some(frame) = ghost.sample(ticks as f32 + (accumtime / tick) as f32) {
+                        ghostrenderer.draw(&mut prepass, &view, &frame.pos, frame.yaw);
+                    }
+                }
+            }
+            psystem.light_passes(&display, &mut pass_data, &scene.lights, &view, &light_technique);
+
+            psystem.postprocess(&pass_data, &mut target, &cel_technique);
+        };
+        let hudcontext = hud::Context {
+            eyeang: game.players[0].eyeang,
+            player_vel: game.players[0].vel
+        };
+
+        client.hudmanager.draw_elements(&mut target, &hudcontext, &client.hudelements);
+
+        target.finish().unwrap();
+    }
+        
+}
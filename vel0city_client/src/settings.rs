@@ -0,0 +1,11 @@
+use glutin::VirtualKeyCode;
+
+pub struct InputSettings {
+    pub sensitivity: f32,
+
+    pub forwardkey: VirtualKeyCode,
+    pub backkey: VirtualKeyCode,
+    pub leftkey: VirtualKeyCode,
+    pub rightkey: VirtualKeyCode,
+    pub jumpkey: VirtualKeyCode,
+}
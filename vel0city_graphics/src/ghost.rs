@@ -0,0 +1,113 @@
+use glium;
+use na;
+use na::ToHomogeneous;
+use wavefront_obj;
+use wavefront;
+use std::sync::Arc;
+use std::default::Default;
+use {Model, View};
+
+const GHOST_VERTEX_SHADER: &'static str = "
+#version 330
+uniform mat4 w2s;
+uniform mat4 model;
+in vec3 position;
+in vec2 texcoords;
+out vec2 v_texcoords;
+void main() {
+    v_texcoords = texcoords;
+    gl_Position = w2s * model * vec4(position, 1.0);
+}
+";
+
+// Ghosts go into the same prepass buffers as the map, so they get lit and
+// postprocessed along with it. Their normals and positions have no alpha,
+// which leaves the world's behind them for the light passes.
+const GHOST_FRAGMENT_SHADER: &'static str = "
+#version 330
+uniform sampler2D color;
+uniform float alpha;
+in vec2 v_texcoords;
+out vec4 diffuse_out;
+out vec4 light_out;
+out vec4 normal_out;
+out vec4 position_out;
+void main() {
+    diffuse_out = vec4(texture(color, v_texcoords).rgb, alpha);
+    light_out = vec4(1.0, 1.0, 1.0, alpha);
+    normal_out = vec4(0.0);
+    position_out = vec4(0.0);
+}
+";
+
+/// How see-through ghosts are.
+const GHOST_ALPHA: f32 = 0.35;
+
+/// A box the size of a player, as a Wavefront OBJ.
+fn ghost_obj(halfextents: &na::Vec3<f32>) -> String {
+    let mut obj = String::new();
+    for &x in &[-halfextents.x, halfextents.x] {
+        for &y in &[-halfextents.y, halfextents.y] {
+            for &z in &[-halfextents.z, halfextents.z] {
+                obj.push_str(&format!("v {} {} {}\n", x, y, z));
+            }
+        }
+    }
+    // Vertex n (1-based) is at (x, y, z) = bits 2, 1, 0 of n - 1.
+    let quads = [
+        (1, 2, 4, 3), (5, 7, 8, 6), // -x, +x
+        (1, 5, 6, 2), (3, 4, 8, 7), // -y, +y
+        (1, 3, 7, 5), (2, 6, 8, 4), // -z, +z
+    ];
+    for &(a, b, c, d) in &quads {
+        obj.push_str(&format!("f {} {} {}\nf {} {} {}\n", a, b, c, a, c, d));
+    }
+    obj
+}
+
+/// Draws translucent stand-ins for players, e.g. for racing a previous run.
+pub struct GhostRenderer {
+    model: Model,
+}
+impl GhostRenderer {
+    pub fn new(display: &glium::Display, halfextents: &na::Vec3<f32>) -> GhostRenderer {
+        let objset = wavefront_obj::obj::parse(ghost_obj(halfextents)).unwrap();
+        let program = glium::Program::from_source(
+            display,
+            GHOST_VERTEX_SHADER,
+            GHOST_FRAGMENT_SHADER,
+            None
+            ).unwrap();
+        let texture = glium::Texture2d::new(display, vec![vec![(128u8, 255u8, 255u8)]]);
+
+        GhostRenderer {
+            model: wavefront::obj_to_model(&objset.objects[0], Arc::new(program), texture, display)
+        }
+    }
+
+    /// Draws a ghost with its center at `pos`, facing `yaw`, into the prepass
+    /// framebuffer after the map, so walls hide it.
+    pub fn draw<S: glium::Surface>(&self, surface: &mut S, view: &View, pos: &na::Pnt3<f32>, yaw: f32) {
+        let drawparams = glium::DrawParameters {
+            depth_test: glium::DepthTest::IfLess,
+            depth_write: false,
+            blending_function: Some(glium::BlendingFunction::Addition {
+                source: glium::LinearBlendingFactor::SourceAlpha,
+                destination: glium::LinearBlendingFactor::OneMinusSourceAlpha,
+            }),
+            ..Default::default()
+        };
+        let model = na::Iso3::new(pos.to_vec(), na::Vec3::new(0.0, yaw, 0.0)).to_homogeneous();
+        let uniforms = uniform! {
+            w2s: *(view.w2s).as_array(),
+            model: *model.as_array(),
+            color: &self.model.texture,
+            alpha: GHOST_ALPHA
+        };
+        surface.draw(&self.model.mesh,
+                     &self.model.indices,
+                     &*self.model.program,
+                     &uniforms,
+                     &drawparams).unwrap();
+    }
+}
@@ -4,16 +4,22 @@ extern crate nalgebra as na;
 extern crate wavefront_obj;
 extern crate vel0city_map as map;
 extern crate vel0city_base;
+extern crate image;
 
 
 use glium::Surface;
-use map::GraphicsMap;
+use mapmodel::{
+    GraphicsMap,
+    MapVertex
+};
 use std::sync::Arc;
 use std::default::Default;
 
 pub mod wavefront;
 pub mod hud;
 pub mod passes;
+pub mod mapmodel;
+pub mod ghost;
 
 #[derive(Copy, Clone)]
 pub struct Vertex {
@@ -46,10 +52,70 @@ pub struct Scene {
     pub lights: Vec<Light>,
 }
 
-pub fn draw_scene<S: glium::Surface>(surface: &mut S,
+pub fn draw_scene<S: glium::Surface>(display: &glium::Display,
+                                     surface: &mut S,
                                      scene: &Scene,
                                      view: &View) {
     draw_map(surface, &scene.map, view);
+    draw_billboards(display, surface, &scene.map, view);
+}
+
+/// Half the width of a billboard, in world units.
+const BILLBOARD_SIZE: f32 = 8.0;
+
+fn draw_billboards<S: glium::Surface>(display: &glium::Display, surface: &mut S, map: &GraphicsMap, view: &View) {
+    if map.billboards.is_empty() {
+        return;
+    }
+    let drawparams = glium::DrawParameters {
+        depth_test: glium::DepthTest::IfLess,
+        depth_write: false,
+        ..Default::default()
+    };
+
+    // The rows of the camera's rotation are its axes in world space.
+    let right = na::Vec3::new(view.cam[(0, 0)], view.cam[(0, 1)], view.cam[(0, 2)]) * BILLBOARD_SIZE;
+    let up = na::Vec3::new(view.cam[(1, 0)], view.cam[(1, 1)], view.cam[(1, 2)]) * BILLBOARD_SIZE;
+    let back = na::Vec3::new(view.cam[(2, 0)], view.cam[(2, 1)], view.cam[(2, 2)]);
+
+    // The quads face the camera, so they change every frame. Build them all
+    // at once and draw each one from its own slice of the buffer.
+    let mut quads = Vec::with_capacity(map.billboards.len() * 6);
+    for billboard in &map.billboards {
+        let center = na::Vec3::new(billboard.position[0], billboard.position[1], billboard.position[2]);
+        let corner = |x: f32, y: f32| {
+            let pos = center + right * x + up * y;
+            MapVertex {
+                position: [pos.x, pos.y, pos.z],
+                texcoords: [(x + 1.0) / 2.0, (1.0 - y) / 2.0],
+                lightmaptexcoords: [0.0, 0.0],
+                normal: [back.x, back.y, back.z]
+            }
+        };
+        quads.extend([
+            corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0),
+            corner(-1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0),
+        ].iter().cloned());
+    }
+    let vertices = glium::VertexBuffer::new(display, quads);
+
+    for (i, billboard) in map.billboards.iter().enumerate() {
+        let colorsamp = glium::uniforms::Sampler::new(&map.textures[billboard.texture as usize])
+            .minify_filter(glium::uniforms::MinifySamplerFilter::LinearMipmapLinear);
+        let lmsamp = glium::uniforms::Sampler::new(&map.fullbright);
+        let uniforms = uniform! { 
+            w2s: *(view.w2s).as_array(),
+            cam: *(view.cam).as_array(),
+            model: *na::new_identity::<na::Mat4<_>>(4).as_array(), 
+            diffuse: colorsamp,
+            lightmap: lmsamp
+        };
+        surface.draw(vertices.slice(i * 6 .. (i + 1) * 6).unwrap(),
+                     &glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                     &map.shaders[0],
+                     &uniforms,
+                     &drawparams).unwrap();
+    }
 }
 
 fn draw_map<S: glium::Surface>(surface: &mut S, map: &GraphicsMap, view: &View) {
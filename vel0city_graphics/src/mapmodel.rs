@@ -0,0 +1,70 @@
+use vel0city_base::assets;
+use glium;
+use image;
+use map::{self, q3_import};
+use map::q3_import::BspError;
+
+#[derive(Copy, Clone, Debug)]
+pub struct MapVertex {
+    pub position: [f32; 3],
+    pub texcoords: [f32; 2],
+    pub lightmaptexcoords: [f32; 2],
+    pub normal: [f32; 3]
+}
+implement_vertex!(MapVertex, position, texcoords, lightmaptexcoords, normal);
+
+pub struct GraphicsMap {
+    pub vertices: glium::VertexBuffer<MapVertex>,
+    pub indices: glium::IndexBuffer<u32>,
+    pub faces: Vec<map::MapFace>, 
+    pub billboards: Vec<map::MapBillboard>,
+    /// Faces of unknown types, which don't get drawn.
+    pub skipped_faces: Vec<u32>,
+    pub textures: Vec<glium::Texture2d>,
+    pub lightmaps: Vec<glium::Texture2d>,
+    /// A plain white lightmap, for things that don't have one.
+    pub fullbright: glium::Texture2d,
+    pub shaders: Vec<glium::Program>,
+}
+
+pub fn import_graphics_model(data: &[u8], display: &glium::Display, patch_level: u32) -> Result<GraphicsMap, BspError> {
+    let surfaces = try!(q3_import::import_surfaces(data, patch_level));
+
+    let loaded_textures = surfaces.textures.iter().map(|name| {
+        let contents = assets::load_bin_asset(&(name.clone() + ".png")).unwrap_or_else(|_| assets::load_bin_asset("textures/radiant/notex.png").unwrap());
+        let image = image::load(::std::io::Cursor::new(contents), image::PNG).unwrap();
+        let texture = glium::Texture2d::new(display, image);
+        texture
+    }).collect();
+    let loaded_lightmaps = surfaces.lightmaps.into_iter().map(|lm|  
+                                                     glium::Texture2d::new(display, lm)
+                                                    ).collect();
+    let fullbright = glium::Texture2d::new(display, vec![vec![(255u8, 255u8, 255u8)]]);
+    let loaded_vertices = surfaces.vertices.iter().map(|vert| {
+        MapVertex {
+            position: vert.position,
+            texcoords: vert.texcoords,
+            lightmaptexcoords: vert.lightmaptexcoords,
+            normal: vert.normal
+        }
+    }).collect::<Vec<_>>();
+
+    let main_program = glium::Program::from_source(
+        display,
+        &assets::load_str_asset("shaders/prepass/vertex.glsl").unwrap(),
+        &assets::load_str_asset("shaders/prepass/fragment.glsl").unwrap(),
+        None
+        ).unwrap();
+
+    Ok(GraphicsMap {
+        vertices: glium::VertexBuffer::new(display, loaded_vertices),
+        indices: glium::IndexBuffer::new(display, glium::index::PrimitiveType::TrianglesList, surfaces.indices),
+        shaders: vec![main_program],
+        textures: loaded_textures,
+        lightmaps: loaded_lightmaps,
+        fullbright: fullbright,
+        faces: surfaces.faces,
+        billboards: surfaces.billboards,
+        skipped_faces: surfaces.skipped_faces,
+    })
+}
@@ -6,9 +6,15 @@ use cast::{
     CastResult,
     combine_results,
 };
+use {
+    Contents,
+    SurfaceFlags
+};
 
 
 const EPS: f32 = 1.0/8.0;
+/// How far past the surface `Brush::push_out` leaves a box.
+const UNSTICK_EPS: f32 = 1.0/32.0;
 
 fn signcpy(n: f32, from: f32) -> f32 {
     if from >= 0.0 {
@@ -39,6 +45,45 @@ impl Plane {
     fn dist_to_point(&self, point: &na::Pnt3<f32>) -> f32 {
         na::dot(&self.norm, point.as_vec()) - self.dist
     }
+
+    /// How far a box with the given half-extents reaches along this plane's normal.
+    pub fn box_pad(&self, halfextents: &na::Vec3<f32>) -> f32 {
+        na::abs(&(halfextents.x * self.norm.x)) +
+            na::abs(&(halfextents.y * self.norm.y)) + 
+            na::abs(&(halfextents.z * self.norm.z))
+    }
+
+    /// Classifies a box swept along `ray` against this plane.
+    ///
+    /// If the box touches the plane at some point, the result's `toi` is the fraction
+    /// of the ray at which it first does (0 if it starts out touching). A box sliding
+    /// along the plane while touching it spans it from the start, and a box that never
+    /// gets within reach of the plane is wholly on one side, however it moves.
+    pub fn test_ray(&self, ray: &Ray) -> PlaneTestResult {
+        self.test_ray_padded(ray, 0.0)
+    }
+
+    /// Like `test_ray`, but the box counts as touching the plane once it's within `margin` of it.
+    pub fn test_ray_padded(&self, ray: &Ray, margin: f32) -> PlaneTestResult {
+        let end = (ray.orig.to_vec() + ray.dir).to_pnt();
+        let (d1, d2) = (self.dist_to_point(&ray.orig), self.dist_to_point(&end));
+        let pad = self.box_pad(&ray.halfextents) + margin;
+
+        if d1 > pad && d2 > pad {
+            PlaneTestResult::Front
+        } else if d1 < -pad && d2 < -pad {
+            PlaneTestResult::Back
+        } else {
+            let toi = if d1 > pad {
+                (d1 - pad) / (d1 - d2)
+            } else if d1 < -pad {
+                (d1 + pad) / (d1 - d2)
+            } else {
+                0.0
+            };
+            PlaneTestResult::Span(CastResult::new(ray, toi, self.norm, self.dist))
+        }
+    }
 }
 
 pub type NodeIndex = i32;
@@ -62,27 +107,73 @@ pub struct Leaf {
 
 #[derive(Debug)]
 pub struct Brush {
-    pub sides: Vec<BrushSide>
+    pub sides: Vec<BrushSide>,
+    /// What the whole brush is made of, as Q3 contents flags.
+    pub contents: i32,
+    /// Bounding box, from the brush's axial sides.
+    pub mins: na::Vec3<f32>,
+    pub maxs: na::Vec3<f32>,
 }
 impl Brush {
+    /// Makes a brush, working out its bounds from its axial sides. Both Q3 brushes and
+    /// ours have them, but a brush without one is assumed to go on forever that way.
+    pub fn new(sides: Vec<BrushSide>, contents: i32) -> Brush {
+        let inf = ::std::f32::INFINITY;
+        let mut mins = na::Vec3::new(-inf, -inf, -inf);
+        let mut maxs = na::Vec3::new(inf, inf, inf);
+        for side in &sides {
+            for axis in 0..3 {
+                let n = side.plane.norm[axis];
+                if na::approx_eq(&n, &1.0) {
+                    maxs[axis] = f32::min(maxs[axis], side.plane.dist);
+                } else if na::approx_eq(&n, &-1.0) {
+                    mins[axis] = f32::max(mins[axis], -side.plane.dist);
+                }
+            }
+        }
+        Brush {
+            sides: sides,
+            contents: contents,
+            mins: mins,
+            maxs: maxs
+        }
+    }
+
     pub fn cast_ray(&self, ray: &Ray, (start, end): (f32, f32)) -> Option<CastResult> {
+        if self.contents as u32 & ray.mask.bits() == 0 {
+            return None;
+        }
+        if !ray.may_touch(&self.mins, &self.maxs) {
+            return None;
+        }
+
         let mut sf = -1.0;
         let mut ef = 1.0;
         let mut norm = na::zero();
+        let mut dist = 0.0;
+        let mut surface = 0;
+        // Whether the box starts and ends outside of the brush. Boxes that are merely
+        // touching it, up to EPS deep, are still outside.
+        let mut startout = false;
+        let mut getout = false;
+        let endpos = (ray.orig.to_vec() + ray.dir).to_pnt();
         for side in &self.sides {
-            if !(side.contents & 1 == 1) {
-                continue;
+            // Sides the box stays more than EPS clear of mean it misses the whole brush,
+            // and sides it stays well behind don't matter.
+            match side.plane.test_ray_padded(ray, EPS) {
+                PlaneTestResult::Front => return None,
+                PlaneTestResult::Back => continue,
+                PlaneTestResult::Span(_) => ()
             }
-
-            let pad = na::abs(&(ray.halfextents.x * side.plane.norm.x)) +
-                na::abs(&(ray.halfextents.y * side.plane.norm.y)) + 
-                na::abs(&(ray.halfextents.z * side.plane.norm.z));
-
-            let startpos = (ray.orig.to_vec()).to_pnt();
-            let endpos = (ray.orig.to_vec() + ray.dir).to_pnt();
-
-            let d1 = side.plane.dist_to_point(&startpos) - pad;
+            let pad = side.plane.box_pad(&ray.halfextents);
+            let d1 = side.plane.dist_to_point(&ray.orig) - pad;
             let d2 = side.plane.dist_to_point(&endpos) - pad;
+            if d1 > -EPS {
+                startout = true;
+            }
+            if d2 > 0.0 {
+                getout = true;
+            }
             if d1 > 0.0 && (d2 >= d1 || d2 >= EPS) { 
                 return None;
             } else if d1 <= 0.0 && d2 <= 0.0 {
@@ -94,6 +185,8 @@ impl Brush {
                 if frac > sf {
                     sf = frac;
                     norm = side.plane.norm;
+                    dist = side.plane.dist;
+                    surface = side.flags;
                 }
             } else {
                 let frac = (d1 + EPS) / (d1 - d2);
@@ -103,15 +196,90 @@ impl Brush {
                 }
             }
         }
+        let contents = Contents::from_bits_truncate(self.contents as u32);
+        if !startout {
+            // Getting out of a brush doesn't stop the box, but it still started out inside it.
+            if getout {
+                return Some(CastResult {
+                    start_solid: true,
+                    contents: contents,
+                    ..CastResult::new(ray, 1.0, na::zero(), 0.0)
+                });
+            }
+            // It's a hit if the box is stuck in it.
+            if start > 0.0 {
+                return None;
+            }
+            return Some(CastResult {
+                start_solid: true,
+                all_solid: true,
+                contents: contents,
+                ..CastResult::new(ray, 0.0, na::zero(), 0.0)
+            });
+        }
         if sf > -1.0 && sf <= ef && sf >= start && sf <= end {
             return Some(CastResult {
-                toi: sf,
-                norm: norm,
-                entity: None, 
+                contents: contents,
+                surface: SurfaceFlags::from_bits_truncate(surface as u32),
+                ..CastResult::new(ray, sf, norm, dist)
             });
         }
         None
     }
+
+    /// Whether a box centered on `center` is (strictly) inside this brush.
+    pub fn overlaps_box(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) -> bool {
+        let (lo, hi) = (center.to_vec() - *halfextents, center.to_vec() + *halfextents);
+        if lo.x >= self.maxs.x || lo.y >= self.maxs.y || lo.z >= self.maxs.z ||
+           hi.x <= self.mins.x || hi.y <= self.mins.y || hi.z <= self.mins.z {
+            return false;
+        }
+        self.sides.iter().all(|side| {
+            side.plane.dist_to_point(center) - side.plane.box_pad(halfextents) < 0.0
+        })
+    }
+
+    /// The shortest translation that gets a box centered on `center` out of this brush,
+    /// assuming it's inside it.
+    pub fn push_out(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) -> na::Vec3<f32> {
+        let (depth, norm) = self.sides.iter().fold((::std::f32::INFINITY, na::zero()), |(best, norm), side| {
+            let depth = side.plane.box_pad(halfextents) - side.plane.dist_to_point(center);
+            if depth < best {
+                (depth, side.plane.norm)
+            } else {
+                (best, norm)
+            }
+        });
+        norm * (depth + UNSTICK_EPS)
+    }
+
+    /// Checks whether a box swept along the ray overlaps this brush at any point,
+    /// including at the very start. Contents are ignored, so this works for triggers.
+    pub fn touches_ray(&self, ray: &Ray) -> bool {
+        if !ray.may_touch(&self.mins, &self.maxs) {
+            return false;
+        }
+        let endpos = (ray.orig.to_vec() + ray.dir).to_pnt();
+
+        let mut enter: f32 = 0.0;
+        let mut leave: f32 = 1.0;
+        for side in &self.sides {
+            match side.plane.test_ray(ray) {
+                PlaneTestResult::Front => return false,
+                PlaneTestResult::Back => continue,
+                PlaneTestResult::Span(_) => ()
+            }
+            let pad = side.plane.box_pad(&ray.halfextents);
+            let d1 = side.plane.dist_to_point(&ray.orig) - pad;
+            let d2 = side.plane.dist_to_point(&endpos) - pad;
+            if d1 > 0.0 {
+                enter = f32::max(enter, d1 / (d1 - d2));
+            } else if d2 > 0.0 {
+                leave = f32::min(leave, d1 / (d1 - d2));
+            }
+        }
+        enter <= leave
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -134,6 +302,45 @@ impl Tree {
         &self.leaves[(-nodeidx - 1) as usize]
     }
 
+    /// Combined contents of every brush that a box centered on `center` is inside of.
+    pub fn box_contents(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) -> i32 {
+        self.brushes_in_box(center, halfextents).iter()
+            .fold(0, |contents, &brush| contents | self.brushes[brush as usize].contents)
+    }
+
+    /// Every brush that a box centered on `center` is inside of.
+    pub fn brushes_in_box(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) -> Vec<u32> {
+        let mut brushes = vec![];
+        self.brushes_in_box_recursive(center, halfextents, 0, &mut brushes);
+        brushes
+    }
+
+    fn brushes_in_box_recursive(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>, nodeidx: NodeIndex, out: &mut Vec<u32>) {
+        if nodeidx < 0 {
+            let leaf = self.get_leaf(nodeidx);
+            let leafbrushes = &self.leafbrushes[leaf.leafbrush as usize..(leaf.leafbrush + leaf.n_leafbrushes) as usize];
+            for &brush in leafbrushes {
+                // Brushes can be in more than one leaf.
+                if !out.contains(&brush) && self.brushes[brush as usize].overlaps_box(center, halfextents) {
+                    out.push(brush);
+                }
+            }
+            return;
+        }
+
+        let InnerNode { ref plane, pos, neg } = self.inodes[nodeidx as usize];
+        let d = plane.dist_to_point(center);
+        let pad = plane.box_pad(halfextents);
+        if d > pad {
+            self.brushes_in_box_recursive(center, halfextents, pos, out)
+        } else if d < -pad {
+            self.brushes_in_box_recursive(center, halfextents, neg, out)
+        } else {
+            self.brushes_in_box_recursive(center, halfextents, pos, out);
+            self.brushes_in_box_recursive(center, halfextents, neg, out);
+        }
+    }
+
     pub fn cast_ray(&self, ray: &Ray) -> Option<CastResult> {
         self.cast_ray_recursive(ray, 0, (0.0, 1.0), (ray.orig, (ray.orig.to_vec() + ray.dir).to_pnt()))
     }
@@ -155,69 +362,82 @@ impl Tree {
             let mut best = None;
             for &leafbrush in &self.leafbrushes[leaf.leafbrush as usize..(leaf.leafbrush + leaf.n_leafbrushes) as usize] {
                 let brush = &self.brushes[leafbrush as usize];
-                let result = brush.cast_ray(ray, (start, end)); 
+                let result = brush.cast_ray(ray, (start, end))
+                    .map(|result| CastResult { brush: Some(leafbrush), ..result });
                 best = combine_results(result, best);
             }
             return best ;
         }
 
         let InnerNode { ref plane, pos, neg } = self.inodes[nodeidx as usize];
-        
-        let d1 = plane.dist_to_point(&startpos);
-        let d2 = plane.dist_to_point(&endpos);
-
-        let pad = na::abs(&(ray.halfextents.x * plane.norm.x)) +
-            na::abs(&(ray.halfextents.y * plane.norm.y)) + 
-            na::abs(&(ray.halfextents.z * plane.norm.z)) + 0.5 * EPS;
 
-
-        // How does the ray interact with this plane?
-        if d1 > pad && d2 > pad {
+        // How does this part of the ray interact with this plane?
+        let segment = Ray {
+            orig: startpos,
+            dir: endpos - startpos,
+            halfextents: ray.halfextents,
+            mask: ray.mask
+        };
+        let pad = plane.box_pad(&ray.halfextents) + 0.5 * EPS;
+        match plane.test_ray_padded(&segment, 0.5 * EPS) {
             // Then just check the front subtree.
-            self.cast_ray_recursive(&ray, pos, (start, end), (startpos, endpos))
-        } else if d1 < -pad && d2 < -pad {
-            self.cast_ray_recursive(&ray, neg, (start, end), (startpos, endpos))
-        } else {
-            let td = d1 - d2;
-            let coincident;
-            let (ns, fs);
-            if d1 < d2 {
-                coincident = true;
-                ns = (d1 - pad + EPS) / td;
-                fs = (d1 + pad + EPS) / td;
-            } else if d2 < d1 {
-                coincident = false;
-                ns = (d1 + pad + EPS) / td;
-                fs = (d1 - pad - EPS) / td;
-            } else {
-                coincident = true ;
-                ns = 1.0;
-                fs = 0.0;
-            }
+            PlaneTestResult::Front => self.cast_ray_recursive(&ray, pos, (start, end), (startpos, endpos)),
+            PlaneTestResult::Back => self.cast_ray_recursive(&ray, neg, (start, end), (startpos, endpos)),
+            PlaneTestResult::Span(_) => {
+                let (d1, d2) = (plane.dist_to_point(&startpos), plane.dist_to_point(&endpos));
+                let td = d1 - d2;
+                let coincident;
+                let (ns, fs);
+                if d1 < d2 {
+                    coincident = true;
+                    ns = (d1 - pad + EPS) / td;
+                    fs = (d1 + pad + EPS) / td;
+                } else if d2 < d1 {
+                    coincident = false;
+                    ns = (d1 + pad + EPS) / td;
+                    fs = (d1 - pad - EPS) / td;
+                } else {
+                    coincident = true ;
+                    ns = 1.0;
+                    fs = 0.0;
+                }
 
-            let ns = na::clamp(ns, 0.0, 1.0);
-            let fs = na::clamp(fs, 0.0, 1.0);
+                let ns = na::clamp(ns, 0.0, 1.0);
+                let fs = na::clamp(fs, 0.0, 1.0);
 
-            let ns = start + (end - start) * ns;
-            let fs = start + (end - start) * fs;
+                let ns = start + (end - start) * ns;
+                let fs = start + (end - start) * fs;
 
-            let (near, far) = if coincident {
-                (neg, pos) 
-            } else {
-                (pos, neg)
-            };
+                let (near, far) = if coincident {
+                    (neg, pos) 
+                } else {
+                    (pos, neg)
+                };
 
-            let (nearbounds, farbounds) =
-                ((start, ns), (fs, end));
+                let (nearbounds, farbounds) =
+                    ((start, ns), (fs, end));
 
-            let nmid = (startpos.to_vec() + ray.dir * ns).to_pnt();
-            let fmid = (startpos.to_vec() + ray.dir * fs).to_pnt();
+                // ns and fs are fractions of the whole ray, not of this part of it.
+                let nmid = (ray.orig.to_vec() + ray.dir * ns).to_pnt();
+                let fmid = (ray.orig.to_vec() + ray.dir * fs).to_pnt();
 
-            combine_results(self.cast_ray_recursive(ray, near, nearbounds, (startpos, nmid)), self.cast_ray_recursive(ray, far, farbounds, (fmid, endpos)))
+                combine_results(self.cast_ray_recursive(ray, near, nearbounds, (startpos, nmid)), self.cast_ray_recursive(ray, far, farbounds, (fmid, endpos)))
+            }
         }
     }
 }
 
+/// Two 32 unit cubes, centered on (-64, 0, 0) and (64, 0, 0).
+#[cfg(test)]
+pub fn test_tree() -> Tree {
+    use builder::MapBuilder;
+
+    MapBuilder::new()
+        .add_box(na::Vec3::new(-80.0, -16.0, -16.0), na::Vec3::new(-48.0, 16.0, 16.0))
+        .add_box(na::Vec3::new(48.0, -16.0, -16.0), na::Vec3::new(80.0, 16.0, 16.0))
+        .build()
+        .bsp
+}
 
 #[cfg(test)]
 pub mod test {
@@ -227,13 +447,15 @@ pub mod test {
     };
     use super::{
         test_tree,
+        Brush,
         Plane,
         PlaneTestResult
 
     };
-    use super::cast::{
+    use cast::{
         Ray,
     };
+    use MASK_PLAYERSOLID;
 
     macro_rules! assert_castresult {
         ($e: expr, $toi: expr, $norm: expr) => {
@@ -266,6 +488,7 @@ pub mod test {
             orig: na::Pnt3::new(-0.5, 0.0, 0.0),
             dir: na::Vec3::new(1.0, 0.0, 0.0),
             halfextents: na::zero(),
+            mask: MASK_PLAYERSOLID
         });
 
         match result {
@@ -284,24 +507,46 @@ pub mod test {
             dist: 16.0,
         };
 
+        // Sliding along the plane, touching it the whole way. It's touching from the
+        // very start, so that's when the first contact is.
         let result = plane.test_ray(&Ray {
             orig: na::Pnt3::new(16.1, 0.0, 0.0),
             dir: na::Vec3::new(0.0, 0.0, 1.0),
             halfextents: na::Vec3::new(1.0, 1.0, 1.0),
+            mask: MASK_PLAYERSOLID
         });
 
         match result {
             PlaneTestResult::Span(c) => {
-                assert_approx_eq!(c.toi, 0.5);
+                assert_approx_eq!(c.toi, 0.0);
                 assert_approx_eq!(c.norm, plane.norm);
             },
             x => panic!("{:?}", x)
         };
 
+        // Moving towards the plane, but stopping well short of it: the box never
+        // gets past x = 1.6, and the plane is at x = 16.
+        let result = plane.test_ray(&Ray {
+            orig: na::Pnt3::new(0.1, 0.0, 0.0),
+            dir: na::Vec3::new(1.0, 0.0, 0.0),
+            halfextents: na::Vec3::new(0.5, 0.0, 0.0),
+            mask: MASK_PLAYERSOLID
+        });
+        assert_eq!(result, PlaneTestResult::Back);
+    }
+
+    #[test]
+    fn plane_straddletest() {
+        // The same box as above, but with the plane through its starting position.
+        let plane = Plane {
+            norm: na::Vec3::new(1.0, 0.0, 0.0),
+            dist: 0.0,
+        };
         let result = plane.test_ray(&Ray {
             orig: na::Pnt3::new(0.1, 0.0, 0.0),
             dir: na::Vec3::new(1.0, 0.0, 0.0),
             halfextents: na::Vec3::new(0.5, 0.0, 0.0),
+            mask: MASK_PLAYERSOLID
         });
 
         match result {
@@ -312,4 +557,158 @@ pub mod test {
             x => panic!("{:?}", x)
         };
     }
+
+    #[test]
+    fn plane_fronttest() {
+        let plane = Plane {
+            norm: na::Vec3::new(0.0, 1.0, 0.0),
+            dist: 0.0,
+        };
+        let result = plane.test_ray(&Ray {
+            orig: na::Pnt3::new(0.0, 10.0, 0.0),
+            dir: na::Vec3::new(100.0, -5.0, 0.0),
+            halfextents: na::Vec3::new(4.0, 4.0, 4.0),
+            mask: MASK_PLAYERSOLID
+        });
+        assert_eq!(result, PlaneTestResult::Front);
+
+        // Coming down onto the plane, the box touches it once its center is 4 units away.
+        let result = plane.test_ray(&Ray {
+            orig: na::Pnt3::new(0.0, 10.0, 0.0),
+            dir: na::Vec3::new(0.0, -12.0, 0.0),
+            halfextents: na::Vec3::new(4.0, 4.0, 4.0),
+            mask: MASK_PLAYERSOLID
+        });
+        match result {
+            PlaneTestResult::Span(c) => assert_approx_eq!(c.toi, 0.5),
+            x => panic!("{:?}", x)
+        };
+    }
+
+    #[test]
+    fn tree_raytest() {
+        let tree = test_tree();
+        let halfextents = na::Vec3::new(4.0, 4.0, 4.0);
+
+        // Between the cubes.
+        assert!(tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(-32.0, 0.0, 0.0),
+            dir: na::Vec3::new(64.0, 0.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        }).is_none());
+
+        // Into the right one, which the box touches at x = 44.
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, 0.0, 0.0),
+            dir: na::Vec3::new(88.0, 0.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        });
+        assert_castresult!(result, 0.5 - super::EPS / 88.0, na::Vec3::new(-1.0, 0.0, 0.0));
+        let result = result.unwrap();
+        assert_approx_eq!(result.endpos, na::Pnt3::new(44.0 - super::EPS, 0.0, 0.0));
+        assert_approx_eq!(result.dist, -48.0);
+        assert_eq!(result.brush, Some(1));
+        assert!(!result.start_solid);
+
+        // Into the left one from above.
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(-64.0, -40.0, 0.0),
+            dir: na::Vec3::new(0.0, 40.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        });
+        assert_castresult!(result, 0.5 - super::EPS / 40.0, na::Vec3::new(0.0, -1.0, 0.0));
+
+        // Out of the left one and into the right one.
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(-64.0, 0.0, 0.0),
+            dir: na::Vec3::new(128.0, 0.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        }).unwrap();
+        assert_eq!(result.brush, Some(1));
+        assert!(result.start_solid && !result.all_solid);
+
+        // Out of the left one, into open space.
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(-64.0, 0.0, 0.0),
+            dir: na::Vec3::new(0.0, -64.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        }).unwrap();
+        assert_eq!(result.brush, Some(0));
+        assert!(result.start_solid && !result.all_solid);
+        assert_eq!(result.toi, 1.0);
+        assert_eq!(result.norm, na::zero());
+        assert_approx_eq!(result.endpos, na::Pnt3::new(-64.0, -64.0, 0.0));
+
+        // Stuck inside the left one.
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(-64.0, 0.0, 0.0),
+            dir: na::Vec3::new(4.0, 0.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        }).unwrap();
+        assert_eq!(result.brush, Some(0));
+        assert!(result.start_solid && result.all_solid);
+        assert_eq!(result.toi, 0.0);
+
+        // Passing over both.
+        assert!(tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(-128.0, -21.0, 0.0),
+            dir: na::Vec3::new(256.0, 0.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        }).is_none());
+    }
+
+    #[test]
+    fn slide_along_face() {
+        // Resting EPS above the top of the left cube, which is at y = -16, and sliding
+        // across it. Within the casts' margin, the top spans the sweep from the start,
+        // but that's no reason to stop the box.
+        let tree = test_tree();
+        let ray = Ray {
+            orig: na::Pnt3::new(-100.0, -20.0 - super::EPS, 0.0),
+            dir: na::Vec3::new(80.0, 0.0, 0.0),
+            halfextents: na::Vec3::new(4.0, 4.0, 4.0),
+            mask: MASK_PLAYERSOLID
+        };
+        let left = tree.brushes.iter().find(|brush| brush.mins.x < 0.0).unwrap();
+        let top = &left.sides.iter().find(|side| side.plane.norm.y < -0.5).unwrap().plane;
+        match top.test_ray_padded(&ray, super::EPS) {
+            PlaneTestResult::Span(c) => assert_eq!(c.toi, 0.0),
+            x => panic!("{:?}", x)
+        }
+        assert!(left.cast_ray(&ray, (0.0, 1.0)).is_none());
+        assert!(tree.cast_ray(&ray).is_none());
+    }
+
+    #[test]
+    fn brush_bounds() {
+        let tree = test_tree();
+        let left = tree.brushes.iter().find(|brush| brush.mins.x < 0.0).unwrap();
+        assert!(na::approx_eq(&left.mins, &na::Vec3::new(-80.0, -16.0, -16.0)));
+        assert!(na::approx_eq(&left.maxs, &na::Vec3::new(-48.0, 16.0, 16.0)));
+
+        // A brush with no side facing +x goes on forever that way.
+        let open = Brush::new(left.sides.iter()
+                              .filter(|side| side.plane.norm.x < 0.5)
+                              .cloned()
+                              .collect(), left.contents);
+        assert!(open.maxs.x.is_infinite());
+        assert!(na::approx_eq(&open.mins, &left.mins));
+
+        // Sweeps that pass well clear of the brush are ruled out before testing any sides.
+        let ray = Ray {
+            orig: na::Pnt3::new(-64.0, -64.0, 0.0),
+            dir: na::Vec3::new(0.0, 0.0, 64.0),
+            halfextents: na::Vec3::new(4.0, 4.0, 4.0),
+            mask: MASK_PLAYERSOLID
+        };
+        assert!(!ray.may_touch(&left.mins, &left.maxs));
+        assert!(left.cast_ray(&ray, (0.0, 1.0)).is_none());
+    }
 }
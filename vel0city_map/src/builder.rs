@@ -0,0 +1,422 @@
+//! Building maps out of convex brushes, without going through a .bsp file.
+
+use na;
+use bsp;
+use mover::{self, MoverPath};
+use {
+    Contents,
+    SurfaceFlags,
+    CONTENTS_SOLID,
+    CONTENTS_TRIGGER,
+    Map,
+    Model,
+    Entity,
+    EntityKind,
+    SpawnPoint
+};
+
+/// How far outside a brush's planes its vertices may lie.
+const VERTEX_EPS: f32 = 0.01;
+/// How far a brush has to reach past a splitting plane to end up on that side of it.
+const SPLIT_EPS: f32 = 0.01;
+
+/// The planes of an axis-aligned box.
+pub fn box_planes(mins: na::Vec3<f32>, maxs: na::Vec3<f32>) -> Vec<bsp::Plane> {
+    vec![
+        bsp::Plane { norm: na::Vec3::new(1.0, 0.0, 0.0), dist: maxs.x },
+        bsp::Plane { norm: na::Vec3::new(-1.0, 0.0, 0.0), dist: -mins.x },
+        bsp::Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: maxs.y },
+        bsp::Plane { norm: na::Vec3::new(0.0, -1.0, 0.0), dist: -mins.y },
+        bsp::Plane { norm: na::Vec3::new(0.0, 0.0, 1.0), dist: maxs.z },
+        bsp::Plane { norm: na::Vec3::new(0.0, 0.0, -1.0), dist: -mins.z },
+    ]
+}
+
+/// Finds the corners of the convex volume behind all of the given planes.
+pub fn brush_vertices(planes: &[bsp::Plane]) -> Vec<na::Vec3<f32>> {
+    let mut vertices: Vec<na::Vec3<f32>> = vec![];
+    for i in 0..planes.len() {
+        for j in i + 1..planes.len() {
+            for k in j + 1..planes.len() {
+                let (a, b, c) = (&planes[i], &planes[j], &planes[k]);
+                let bc = na::cross(&b.norm, &c.norm);
+                let det = na::dot(&a.norm, &bc);
+                if na::abs(&det) < 0.0001 {
+                    continue;
+                }
+                let point = (bc * a.dist +
+                             na::cross(&c.norm, &a.norm) * b.dist +
+                             na::cross(&a.norm, &b.norm) * c.dist) / det;
+
+                let inside = planes.iter().all(|p| na::dot(&p.norm, &point) - p.dist <= VERTEX_EPS);
+                let seen = vertices.iter().any(|v| na::norm(&(*v - point)) <= VERTEX_EPS);
+                if inside && !seen {
+                    vertices.push(point);
+                }
+            }
+        }
+    }
+    vertices
+}
+
+/// Normalizes and bevels the planes of a brush, returning it along with its corners.
+/// Panics if the planes don't enclose a volume.
+fn make_brush(planes: Vec<bsp::Plane>, contents: Contents, surface: SurfaceFlags) -> (bsp::Brush, Vec<na::Vec3<f32>>) {
+    let mut planes: Vec<bsp::Plane> = planes.into_iter().map(|p| {
+        let len = na::norm(&p.norm);
+        bsp::Plane {
+            norm: p.norm / len,
+            dist: p.dist / len
+        }
+    }).collect();
+
+    let vertices = brush_vertices(&planes);
+    if vertices.len() < 4 {
+        panic!("Brush planes don't enclose a volume: {:?}", planes);
+    }
+
+    // Axial bevels, so that boxes don't catch on sloped planes
+    // far away from the brush itself.
+    let axes = [
+        na::Vec3::new(1.0, 0.0, 0.0),
+        na::Vec3::new(0.0, 1.0, 0.0),
+        na::Vec3::new(0.0, 0.0, 1.0),
+    ];
+    for axis in axes.iter() {
+        for &sign in [1.0, -1.0].iter() {
+            let norm = *axis * sign;
+            if planes.iter().any(|p| na::approx_eq(&p.norm, &norm)) {
+                continue;
+            }
+            let first = na::dot(&norm, &vertices[0]);
+            let dist = vertices.iter().fold(first, |m, v| f32::max(m, na::dot(&norm, v)));
+            planes.push(bsp::Plane { norm: norm, dist: dist });
+        }
+    }
+
+    let brush = bsp::Brush::new(planes.into_iter().map(|plane| bsp::BrushSide {
+        plane: plane,
+        flags: surface.bits() as i32,
+        contents: contents.bits() as i32
+    }).collect(), contents.bits() as i32);
+    (brush, vertices)
+}
+
+/// Puts together a `Map` out of solid brushes and brush entities.
+/// Adding a brush or box with no volume panics straight away; `build` itself doesn't.
+///
+/// ```ignore
+/// let map = MapBuilder::new()
+///     .add_box(na::Vec3::new(-512.0, 0.0, -512.0), na::Vec3::new(512.0, 16.0, 512.0))
+///     .add_spawnpoint(na::Pnt3::new(0.0, -32.0, 0.0), 0.0)
+///     .build();
+/// ```
+pub struct MapBuilder {
+    world: Vec<(bsp::Brush, Vec<na::Vec3<f32>>)>,
+    entities: Vec<(Entity, Vec<(bsp::Brush, Vec<na::Vec3<f32>>)>)>,
+    spawnpoints: Vec<SpawnPoint>,
+}
+impl MapBuilder {
+    pub fn new() -> MapBuilder {
+        MapBuilder {
+            world: vec![],
+            entities: vec![],
+            spawnpoints: vec![],
+        }
+    }
+
+    /// Adds a solid axis-aligned box to the world.
+    pub fn add_box(self, mins: na::Vec3<f32>, maxs: na::Vec3<f32>) -> MapBuilder {
+        self.add_brush(box_planes(mins, maxs))
+    }
+
+    /// Adds a solid convex brush to the world. Each plane's normal points out of the brush,
+    /// and together they have to enclose a finite volume.
+    ///
+    /// # Panics
+    ///
+    /// If the planes don't enclose a volume. Maps are built by hand, usually
+    /// in tests, so this is a bug in whatever is calling it.
+    pub fn add_brush(self, planes: Vec<bsp::Plane>) -> MapBuilder {
+        self.add_brush_with_contents(planes, CONTENTS_SOLID)
+    }
+
+    /// Adds an axis-aligned box to the world that's made of something other than plain solid.
+    pub fn add_box_with_contents(self, mins: na::Vec3<f32>, maxs: na::Vec3<f32>, contents: Contents) -> MapBuilder {
+        self.add_brush_with_contents(box_planes(mins, maxs), contents)
+    }
+
+    /// Adds a convex brush to the world made of `contents`, like `add_brush`.
+    ///
+    /// # Panics
+    ///
+    /// If the planes don't enclose a volume.
+    pub fn add_brush_with_contents(mut self, planes: Vec<bsp::Plane>, contents: Contents) -> MapBuilder {
+        self.world.push(make_brush(planes, contents, SurfaceFlags::empty()));
+        self
+    }
+
+    /// Adds a solid axis-aligned box to the world, with the given flags on all of its sides.
+    pub fn add_box_with_surface(mut self, mins: na::Vec3<f32>, maxs: na::Vec3<f32>, surface: SurfaceFlags) -> MapBuilder {
+        self.world.push(make_brush(box_planes(mins, maxs), CONTENTS_SOLID, surface));
+        self
+    }
+
+    /// Adds a brush entity (e.g. a trigger) occupying an axis-aligned box.
+    /// Its model is filled in when the map gets built.
+    pub fn add_brush_entity(mut self, entity: Entity, mins: na::Vec3<f32>, maxs: na::Vec3<f32>) -> MapBuilder {
+        let brush = make_brush(box_planes(mins, maxs), CONTENTS_TRIGGER, SurfaceFlags::empty());
+        self.entities.push((entity, vec![brush]));
+        self
+    }
+
+    /// Adds a solid box that moves along `path`, like a func_train, or like a func_door
+    /// if the path is triggered.
+    pub fn add_mover(mut self, mins: na::Vec3<f32>, maxs: na::Vec3<f32>, path: MoverPath) -> MapBuilder {
+        let mut entity = Entity::new("func_train", EntityKind::Mover);
+        entity.path = Some(path);
+        let brush = make_brush(box_planes(mins, maxs), CONTENTS_SOLID, SurfaceFlags::empty());
+        self.entities.push((entity, vec![brush]));
+        self
+    }
+
+    pub fn add_spawnpoint(mut self, pos: na::Pnt3<f32>, yaw: f32) -> MapBuilder {
+        self.spawnpoints.push(SpawnPoint { pos: pos, yaw: yaw });
+        self
+    }
+
+    pub fn build(self) -> Map {
+        let mut tree = bsp::Tree {
+            inodes: vec![],
+            leaves: vec![],
+            brushes: vec![],
+            leafbrushes: vec![],
+        };
+
+        let mut splitters: Vec<bsp::Plane> = vec![];
+        for &(ref brush, _) in &self.world {
+            for side in &brush.sides {
+                let plane = &side.plane;
+                let seen = splitters.iter().any(|p| {
+                    (na::approx_eq(&p.norm, &plane.norm) && na::approx_eq(&p.dist, &plane.dist)) ||
+                    (na::approx_eq(&p.norm, &-plane.norm) && na::approx_eq(&p.dist, &-plane.dist))
+                });
+                if !seen {
+                    splitters.push(plane.clone());
+                }
+            }
+        }
+
+        let world_vertices: Vec<Vec<na::Vec3<f32>>> = self.world.iter().map(|&(_, ref v)| v.clone()).collect();
+        let all: Vec<u32> = (0..self.world.len() as u32).collect();
+        let root = build_node(&mut tree, &splitters, &world_vertices, all);
+        if root < 0 {
+            // Casts always start at node 0, so there has to be one.
+            tree.inodes.push(bsp::InnerNode {
+                plane: bsp::Plane { norm: na::Vec3::new(0.0, -1.0, 0.0), dist: 0.0 },
+                pos: root,
+                neg: root
+            });
+        }
+
+        let n_world = self.world.len() as u32;
+        let (mins, maxs) = bounds(&world_vertices);
+        tree.brushes.extend(self.world.into_iter().map(|(brush, _)| brush));
+        let mut models = vec![Model { mins: mins, maxs: maxs, brush: 0, n_brushes: n_world }];
+
+        let mut worldspawn = Entity::new("worldspawn", EntityKind::Worldspawn);
+        worldspawn.model = Some(0);
+        let mut entities = vec![worldspawn];
+
+        for (mut entity, brushes) in self.entities.into_iter() {
+            let vertices: Vec<Vec<na::Vec3<f32>>> = brushes.iter().map(|&(_, ref v)| v.clone()).collect();
+            let (mins, maxs) = bounds(&vertices);
+            entity.model = Some(models.len() as u32);
+            models.push(Model {
+                mins: mins,
+                maxs: maxs,
+                brush: tree.brushes.len() as u32,
+                n_brushes: brushes.len() as u32
+            });
+            tree.brushes.extend(brushes.into_iter().map(|(brush, _)| brush));
+            entities.push(entity);
+        }
+
+        Map {
+            bsp: tree,
+            models: models,
+            movers: mover::find_movers(&entities),
+            entities: entities,
+            spawnpoints: self.spawnpoints,
+        }
+    }
+}
+
+/// The bounding box of some brushes' vertices. Nothing at all is a point at the origin.
+fn bounds(brushes: &[Vec<na::Vec3<f32>>]) -> (na::Vec3<f32>, na::Vec3<f32>) {
+    let mut all = brushes.iter().flat_map(|vertices| vertices.iter());
+    let first = match all.next() {
+        Some(&first) => first,
+        None => return (na::zero(), na::zero())
+    };
+    all.fold((first, first), |(mins, maxs), v| {
+        (na::Vec3::new(f32::min(mins.x, v.x), f32::min(mins.y, v.y), f32::min(mins.z, v.z)),
+         na::Vec3::new(f32::max(maxs.x, v.x), f32::max(maxs.y, v.y), f32::max(maxs.z, v.z)))
+    })
+}
+
+/// Which sides of `plane` the brush with the given vertices reaches into.
+fn plane_sides(plane: &bsp::Plane, vertices: &[na::Vec3<f32>]) -> (bool, bool) {
+    let first = na::dot(&plane.norm, &vertices[0]) - plane.dist;
+    let (min, max) = vertices.iter().fold((first, first), |(min, max), v| {
+        let d = na::dot(&plane.norm, v) - plane.dist;
+        (f32::min(min, d), f32::max(max, d))
+    });
+    let front = max > SPLIT_EPS;
+    let back = min < -SPLIT_EPS;
+    if !front && !back {
+        // Flat on the plane.
+        (true, true)
+    } else {
+        (front, back)
+    }
+}
+
+/// Recursively splits `brushes` until no plane separates them any further,
+/// returning the index of the new node (or leaf, if negative).
+fn build_node(tree: &mut bsp::Tree,
+              splitters: &[bsp::Plane],
+              vertices: &[Vec<na::Vec3<f32>>],
+              brushes: Vec<u32>) -> bsp::NodeIndex {
+    let mut best: Option<(usize, Vec<u32>, Vec<u32>)> = None;
+    for (planeidx, plane) in splitters.iter().enumerate() {
+        let mut front = vec![];
+        let mut back = vec![];
+        for &brush in &brushes {
+            let (f, b) = plane_sides(plane, &vertices[brush as usize]);
+            if f {
+                front.push(brush);
+            }
+            if b {
+                back.push(brush);
+            }
+        }
+        // Every split has to get us closer to leaves with fewer brushes.
+        if front.len() == brushes.len() || back.len() == brushes.len() {
+            continue;
+        }
+        let better = match best {
+            Some((_, ref bf, ref bb)) => front.len() + back.len() < bf.len() + bb.len(),
+            None => true
+        };
+        if better {
+            best = Some((planeidx, front, back));
+        }
+    }
+
+    match best {
+        Some((planeidx, front, back)) => {
+            let nodeidx = tree.inodes.len();
+            tree.inodes.push(bsp::InnerNode {
+                plane: splitters[planeidx].clone(),
+                pos: 0,
+                neg: 0
+            });
+            let pos = build_node(tree, splitters, vertices, front);
+            let neg = build_node(tree, splitters, vertices, back);
+            tree.inodes[nodeidx].pos = pos;
+            tree.inodes[nodeidx].neg = neg;
+            nodeidx as bsp::NodeIndex
+        },
+        None => {
+            tree.leaves.push(bsp::Leaf {
+                leafbrush: tree.leafbrushes.len() as i32,
+                n_leafbrushes: brushes.len() as i32
+            });
+            tree.leafbrushes.extend(brushes.into_iter());
+            -(tree.leaves.len() as bsp::NodeIndex)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use bsp;
+    use cast::Ray;
+    use MASK_PLAYERSOLID;
+    use super::{MapBuilder, box_planes, brush_vertices};
+
+    #[test]
+    fn finds_box_vertices() {
+        let vertices = brush_vertices(&box_planes(na::Vec3::new(-1.0, -2.0, -3.0), na::Vec3::new(1.0, 2.0, 3.0)));
+        assert_eq!(vertices.len(), 8);
+        for v in &vertices {
+            assert_eq!(na::abs(&v.x), 1.0);
+            assert_eq!(na::abs(&v.y), 2.0);
+            assert_eq!(na::abs(&v.z), 3.0);
+        }
+    }
+
+    #[test]
+    fn builds_empty_map() {
+        let map = MapBuilder::new().build();
+        assert_eq!(map.bsp.inodes.len(), 1);
+        assert!(map.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, 0.0, 0.0),
+            dir: na::Vec3::new(0.0, 100.0, 0.0),
+            halfextents: na::Vec3::new(8.0, 8.0, 8.0),
+            mask: MASK_PLAYERSOLID
+        }).is_none());
+    }
+
+    #[test]
+    fn splits_brushes_into_leaves() {
+        // Two pillars and a slope, far apart from each other.
+        let slope = vec![
+            bsp::Plane { norm: na::Vec3::new(1.0, -1.0, 0.0), dist: 0.0 },
+            bsp::Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: 64.0 },
+            bsp::Plane { norm: na::Vec3::new(1.0, 0.0, 0.0), dist: 64.0 },
+            bsp::Plane { norm: na::Vec3::new(-1.0, 0.0, 0.0), dist: 64.0 },
+            bsp::Plane { norm: na::Vec3::new(0.0, 0.0, 1.0), dist: 64.0 },
+            bsp::Plane { norm: na::Vec3::new(0.0, 0.0, -1.0), dist: 64.0 },
+        ];
+        let map = MapBuilder::new()
+            .add_box(na::Vec3::new(-256.0, -64.0, -16.0), na::Vec3::new(-224.0, 64.0, 16.0))
+            .add_box(na::Vec3::new(224.0, -64.0, -16.0), na::Vec3::new(256.0, 64.0, 16.0))
+            .add_brush(slope)
+            .build();
+
+        assert!(map.bsp.leaves.len() > 1);
+        for leaf in &map.bsp.leaves {
+            assert!(leaf.n_leafbrushes <= 1);
+        }
+        // Bevels got added to the slope.
+        assert_eq!(map.bsp.brushes[2].sides.len(), 7);
+
+        let halfextents = na::Vec3::new(8.0, 8.0, 8.0);
+        let cast = |orig: na::Pnt3<f32>, dir: na::Vec3<f32>| map.cast_ray(&Ray {
+            orig: orig,
+            dir: dir,
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        });
+
+        // Into the side of the left pillar.
+        let hit = cast(na::Pnt3::new(-128.0, 0.0, 0.0), na::Vec3::new(-200.0, 0.0, 0.0)).unwrap();
+        assert!(na::approx_eq(&hit.norm, &na::Vec3::new(1.0, 0.0, 0.0)));
+        // The box's side reaches the pillar's after 88 of the 200 units.
+        assert!(hit.toi > 0.43 && hit.toi < 0.45);
+
+        // Into the right pillar.
+        let hit = cast(na::Pnt3::new(128.0, 0.0, 0.0), na::Vec3::new(200.0, 0.0, 0.0)).unwrap();
+        assert!(na::approx_eq(&hit.norm, &na::Vec3::new(-1.0, 0.0, 0.0)));
+
+        // Straight down onto the slope.
+        let hit = cast(na::Pnt3::new(0.0, -100.0, 0.0), na::Vec3::new(0.0, 200.0, 0.0)).unwrap();
+        assert!(hit.norm.y < 0.0 && hit.norm.x > 0.0);
+
+        // Between all of them.
+        assert!(cast(na::Pnt3::new(-128.0, 0.0, 100.0), na::Vec3::new(256.0, 0.0, 0.0)).is_none());
+    }
+}
@@ -1,53 +1,416 @@
 #[macro_use]
-extern crate glium;
-extern crate vel0city_base;
 extern crate nalgebra as na;
 extern crate byteorder;
-extern crate image;
+#[macro_use]
+extern crate bitflags;
+#[cfg(feature = "parallel")]
+extern crate crossbeam;
+#[cfg(feature = "parallel")]
+extern crate num_cpus;
 
 pub mod bsp;
+pub mod builder;
+pub mod mover;
+pub mod patch;
 pub mod q3_import;
 
+use std::collections::HashMap;
 use cast::{
     CastResult,
-    Ray
+    Ray,
+    combine_results
 };
 
+bitflags! {
+    /// What a brush is made of, using Quake 3's values.
+    flags Contents: u32 {
+        const CONTENTS_SOLID = 0x1,
+        const CONTENTS_LAVA = 0x8,
+        const CONTENTS_SLIME = 0x10,
+        const CONTENTS_WATER = 0x20,
+        const CONTENTS_FOG = 0x40,
+        const CONTENTS_PLAYERCLIP = 0x10000,
+        const CONTENTS_TRIGGER = 0x40000000,
+
+        /// What players collide with.
+        const MASK_PLAYERSOLID = CONTENTS_SOLID.bits | CONTENTS_PLAYERCLIP.bits,
+        /// What shots (e.g. the grapple) collide with.
+        const MASK_SHOT = CONTENTS_SOLID.bits,
+        /// Liquids players can swim in.
+        const MASK_WATER = CONTENTS_WATER.bits | CONTENTS_LAVA.bits | CONTENTS_SLIME.bits,
+    }
+}
+
+bitflags! {
+    /// Properties of a brush side's surface, using Quake 3's values.
+    flags SurfaceFlags: u32 {
+        /// No falling damage.
+        const SURF_NODAMAGE = 0x1,
+        /// No friction.
+        const SURF_SLICK = 0x2,
+        const SURF_SKY = 0x4,
+        const SURF_LADDER = 0x8,
+        /// Shots don't stick to it.
+        const SURF_NOIMPACT = 0x10,
+        const SURF_NOMARKS = 0x20,
+        const SURF_NODRAW = 0x80,
+        /// Doesn't collide with anything.
+        const SURF_NONSOLID = 0x4000,
+    }
+}
+
+/// What's at a point, or inside a box.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentsResult {
+    pub contents: Contents,
+    /// Trigger entities overlapping it. `contents` includes
+    /// `CONTENTS_TRIGGER` if there are any.
+    pub entities: Vec<u32>,
+}
+
 pub struct Model {
+    /// Bounds of the model's brushes, at their compiled location.
+    pub mins: na::Vec3<f32>,
+    pub maxs: na::Vec3<f32>,
     pub brush: u32,
     pub n_brushes: u32 
 }
+#[derive(Clone, Debug)]
 pub struct Entity {
-    pub model: u32,
+    pub classname: String,
+    /// Brush model used by this entity, if it's a brush entity.
+    pub model: Option<u32>,
+    /// Origin of the entity, already converted to engine coordinates.
+    pub origin: Option<na::Pnt3<f32>>,
+    /// Facing around the vertical axis, in degrees, exactly as given in the map.
+    pub angle: f32,
+    pub target: Option<String>,
+    pub targetname: Option<String>,
+    /// How the entity moves, if it's a mover.
+    pub path: Option<mover::MoverPath>,
+    /// Every key/value pair the entity was declared with, including the ones above.
+    pub properties: HashMap<String, String>,
     pub kind: EntityKind
 }
-#[derive(PartialEq)]
+impl Entity {
+    /// An entity with nothing but a classname, for building maps by hand.
+    pub fn new(classname: &str, kind: EntityKind) -> Entity {
+        let mut properties = HashMap::new();
+        properties.insert("classname".to_string(), classname.to_string());
+        Entity {
+            classname: classname.to_string(),
+            model: None,
+            origin: None,
+            angle: 0.0,
+            target: None,
+            targetname: None,
+            path: None,
+            properties: properties,
+            kind: kind
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum EntityKind {
+    Worldspawn,
     OutOfBounds,
-    Goal
+    Start,
+    Goal,
+    Checkpoint,
+    /// Launches players so that the top of their arc is at `target`.
+    JumpPad {
+        target: na::Pnt3<f32>
+    },
+    /// Moves players to `dest`, facing `yaw`.
+    Teleporter {
+        dest: na::Pnt3<f32>,
+        yaw: f32
+    },
+    /// A solid brush entity following `Entity::path`.
+    ///
+    /// Trains run their path from the moment the map loads, like in Quake 3.
+    /// Doors and plats wait until a player touches them; see `Map::trigger_movers`.
+    /// Nothing fires targets, so doors that should only open when targeted open on touch too.
+    Mover,
+    /// Anything that doesn't mean anything to the game (yet).
+    Other
+}
+impl EntityKind {
+    /// Whether entities of this kind are non-solid volumes that react to players touching them.
+    pub fn is_trigger(&self) -> bool {
+        match *self {
+            EntityKind::OutOfBounds |
+            EntityKind::Start |
+            EntityKind::Goal |
+            EntityKind::Checkpoint |
+            EntityKind::JumpPad { .. } |
+            EntityKind::Teleporter { .. } => true,
+            EntityKind::Worldspawn | EntityKind::Mover | EntityKind::Other => false
+        }
+    }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct SpawnPoint {
+    pub pos: na::Pnt3<f32>,
+    /// Heading in radians, as a rotation around the vertical axis.
+    /// A yaw of 0 faces down -Z, just like the client's view angles.
+    pub yaw: f32,
+}
+impl SpawnPoint {
+    pub fn get_ang(&self) -> na::UnitQuat<f32> {
+        na::UnitQuat::new(na::Vec3::new(0.0, self.yaw, 0.0))
+    }
+}
+
+/// How close a box has to get to a door or plat to set it off.
+const MOVER_TOUCH_MARGIN: f32 = 1.0;
+
+/// How many times `Map::unstick` pushes a box before giving up.
+const UNSTICK_ITERATIONS: u32 = 4;
+
+/// Batches smaller than this aren't worth starting threads for.
+#[cfg(feature = "parallel")]
+const MIN_PARALLEL_RAYS: usize = 256;
+
 pub struct Map {
     pub bsp: bsp::Tree,
     pub models: Vec<Model>,
-    pub entities: Vec<Entity>
+    pub entities: Vec<Entity>,
+    /// Where players can (re)spawn. info_player_start comes before info_player_deathmatch.
+    pub spawnpoints: Vec<SpawnPoint>,
+    /// Where every moving brush entity currently is.
+    pub movers: Vec<mover::Mover>,
 }
 
 impl Map {
+    /// Picks a spawn point for the given player, cycling through the available ones.
+    /// Maps without any spawn points spawn everybody at the origin.
+    pub fn get_spawnpoint(&self, playeridx: u32) -> SpawnPoint {
+        if self.spawnpoints.is_empty() {
+            SpawnPoint { pos: na::Pnt3::new(0.0, 0.0, 0.0), yaw: 0.0 }
+        } else {
+            self.spawnpoints[playeridx as usize % self.spawnpoints.len()]
+        }
+    }
+
+    /// Moves every mover to where it should be at the given game time.
+    pub fn update_movers(&mut self, time: f32) {
+        for mover in self.movers.iter_mut() {
+            let path = match self.entities[mover.entity as usize].path {
+                Some(ref path) => path,
+                None => continue
+            };
+            let offset = if !path.triggered {
+                path.offset_at(time)
+            } else {
+                match mover.triggered_at.and_then(|at| path.triggered_offset_at(time - at)) {
+                    Some(offset) => offset,
+                    None => {
+                        mover.triggered_at = None;
+                        path.offset_at(0.0)
+                    }
+                }
+            };
+            mover.delta = offset - mover.offset;
+            mover.offset = offset;
+        }
+    }
+
+    /// Sets off every resting door or plat that a box centered on `center` touches,
+    /// so that they start moving with the next `update_movers` after `time`.
+    pub fn trigger_movers(&mut self, time: f32, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) {
+        let reach = *halfextents + na::Vec3::new(MOVER_TOUCH_MARGIN, MOVER_TOUCH_MARGIN, MOVER_TOUCH_MARGIN);
+        let touched: Vec<usize> = self.movers.iter().enumerate().filter(|&(_, mover)| {
+            let triggered = self.entities[mover.entity as usize].path.as_ref().map_or(false, |path| path.triggered);
+            triggered && mover.triggered_at.is_none() && self.mover_overlaps_box(mover, center, &reach)
+        }).map(|(idx, _)| idx).collect();
+        for idx in touched {
+            self.movers[idx].triggered_at = Some(time);
+        }
+    }
+
+    /// The brushes making up an entity's model.
+    pub fn model_brushes(&self, model: u32) -> &[bsp::Brush] {
+        let model = &self.models[model as usize];
+        &self.bsp.brushes[model.brush as usize .. (model.brush + model.n_brushes) as usize]
+    }
+
+    /// Casts against the world and movers. Triggers aren't solid, so casts go right
+    /// through them; use `touch_triggers` to find the ones a ray passes through.
     pub fn cast_ray(&self, ray: &Ray) -> Option<CastResult> {
         let mut best = self.bsp.cast_ray(ray);
+        for mover in &self.movers {
+            best = combine_results(self.cast_mover(mover, ray), best);
+        }
+        best
+    }
+
+    /// Casts a batch of rays, with the same results as casting each one with `cast_ray`.
+    #[cfg(not(feature = "parallel"))]
+    pub fn cast_rays(&self, rays: &[Ray]) -> Vec<Option<CastResult>> {
+        rays.iter().map(|ray| self.cast_ray(ray)).collect()
+    }
+
+    /// Casts a batch of rays, with the same results as casting each one with `cast_ray`.
+    /// Big batches get split across a thread per CPU, since casting doesn't change the map.
+    #[cfg(feature = "parallel")]
+    pub fn cast_rays(&self, rays: &[Ray]) -> Vec<Option<CastResult>> {
+        let threads = num_cpus::get();
+        if rays.len() < MIN_PARALLEL_RAYS || threads < 2 {
+            return rays.iter().map(|ray| self.cast_ray(ray)).collect();
+        }
+        let chunk_size = (rays.len() + threads - 1) / threads;
+        crossbeam::scope(|scope| {
+            let handles: Vec<_> = rays.chunks(chunk_size).map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(|ray| self.cast_ray(ray)).collect::<Vec<_>>()
+                })
+            }).collect();
+            handles.into_iter().flat_map(|handle| handle.join()).collect()
+        })
+    }
+
+    /// Casts against a single mover, by moving the ray into the mover's frame.
+    pub fn cast_mover(&self, mover: &mover::Mover, ray: &Ray) -> Option<CastResult> {
+        let model = match self.entities[mover.entity as usize].model {
+            Some(model) => model,
+            None => return None
+        };
+        let bounds = &self.models[model as usize];
+        if !ray.may_touch(&(bounds.mins + mover.offset), &(bounds.maxs + mover.offset)) {
+            return None;
+        }
+        let local = Ray {
+            orig: (ray.orig.to_vec() - mover.offset).to_pnt(),
+            dir: ray.dir,
+            halfextents: ray.halfextents,
+            mask: ray.mask
+        };
+        let first = self.models[model as usize].brush;
+        let mut best = None;
+        for (idx, brush) in self.model_brushes(model).iter().enumerate() {
+            let result = brush.cast_ray(&local, (0.0, 1.0))
+                .map(|result| CastResult { brush: Some(first + idx as u32), ..result });
+            best = combine_results(result, best);
+        }
+        // Back out of the mover's frame.
+        best.map(|result| CastResult {
+            endpos: (result.endpos.to_vec() + mover.offset).to_pnt(),
+            dist: result.dist + na::dot(&result.norm, &mover.offset),
+            entity: Some(mover.entity),
+            ..result
+        })
+    }
+
+    fn mover_brushes_overlapping<'a>(&'a self, mover: &mover::Mover, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>)
+                                     -> Box<Iterator<Item=&'a bsp::Brush> + 'a> {
+        let brushes = match self.entities[mover.entity as usize].model {
+            Some(model) => self.model_brushes(model),
+            None => &[][..]
+        };
+        let local = (center.to_vec() - mover.offset).to_pnt();
+        let halfextents = *halfextents;
+        Box::new(brushes.iter().filter(move |brush| brush.overlaps_box(&local, &halfextents)))
+    }
+
+    /// Whether a box centered on `center` is inside a mover.
+    pub fn mover_overlaps_box(&self, mover: &mover::Mover, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) -> bool {
+        self.mover_brushes_overlapping(mover, center, halfextents).next().is_some()
+    }
+
+    /// What's at a single point.
+    pub fn point_contents(&self, point: &na::Pnt3<f32>) -> ContentsResult {
+        self.box_contents(point, &na::zero())
+    }
+
+    /// What a box centered on `center` overlaps. Merely touching something doesn't count,
+    /// except for triggers.
+    pub fn box_contents(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>) -> ContentsResult {
+        let mut contents = Contents::from_bits_truncate(self.bsp.box_contents(center, halfextents) as u32);
+        for mover in &self.movers {
+            for brush in self.mover_brushes_overlapping(mover, center, halfextents) {
+                contents.insert(Contents::from_bits_truncate(brush.contents as u32));
+            }
+        }
+        let entities = self.touch_triggers(&Ray {
+            orig: *center,
+            dir: na::zero(),
+            halfextents: *halfextents,
+            mask: Contents::all()
+        });
+        if !entities.is_empty() {
+            contents.insert(CONTENTS_TRIGGER);
+        }
+        ContentsResult {
+            contents: contents,
+            entities: entities
+        }
+    }
+
+    /// How to get a box out of every brush matching `mask` that it's inside of, one way per brush.
+    fn push_outs(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>, mask: Contents) -> Vec<na::Vec3<f32>> {
+        let matches = |brush: &bsp::Brush| brush.contents as u32 & mask.bits() != 0;
+        let mut pushes: Vec<na::Vec3<f32>> = self.bsp.brushes_in_box(center, halfextents).into_iter()
+            .map(|brush| &self.bsp.brushes[brush as usize])
+            .filter(|brush| matches(brush))
+            .map(|brush| brush.push_out(center, halfextents))
+            .collect();
+        for mover in &self.movers {
+            let local = (center.to_vec() - mover.offset).to_pnt();
+            pushes.extend(self.mover_brushes_overlapping(mover, center, halfextents)
+                          .filter(|brush| matches(brush))
+                          .map(|brush| brush.push_out(&local, halfextents)));
+        }
+        pushes
+    }
+
+    /// Finds a short translation that gets a box centered on `center` out of every brush
+    /// matching `mask`. It's zero if the box isn't stuck in anything, and None if there
+    /// doesn't seem to be a way out.
+    pub fn unstick(&self, center: &na::Pnt3<f32>, halfextents: &na::Vec3<f32>, mask: Contents) -> Option<na::Vec3<f32>> {
+        let mut offset = na::zero();
+        for _ in 0..UNSTICK_ITERATIONS {
+            let pos = *center + offset;
+            let mut pushes = self.push_outs(&pos, halfextents, mask);
+            if pushes.is_empty() {
+                return Some(offset);
+            }
+            pushes.sort_by(|a, b| na::sqnorm(a).partial_cmp(&na::sqnorm(b)).unwrap());
+
+            // Getting out of everything at once is best, but if no single push does that,
+            // take the shortest one and see where that leads.
+            let free = pushes.iter().find(|&push| self.push_outs(&(pos + *push), halfextents, mask).is_empty());
+            if let Some(push) = free {
+                return Some(offset + *push);
+            }
+            offset = offset + pushes[0];
+        }
+        None
+    }
+
+    /// Finds every trigger entity touched by a box swept along `ray`.
+    pub fn touch_triggers(&self, ray: &Ray) -> Vec<u32> {
+        let mut touched = vec![];
         for (entityidx, entity) in self.entities.iter().enumerate() {
-            let model = &self.models[entity.model as usize];
-            for brush in &self.bsp.brushes[model.brush as usize .. (model.brush + model.n_brushes) as usize] {
-                let mut brushcast = brush.cast_ray(ray, (0.0, 1.0));
-                if let Some(brushcast) = brushcast.as_mut() {
-                    brushcast.entity = Some(entityidx as u32);
-                }
-                best = cast::combine_results(best, brushcast);
+            if !entity.kind.is_trigger() {
+                continue;
+            }
+            let model = match entity.model {
+                Some(model) => model,
+                None => continue
+            };
+            let bounds = &self.models[model as usize];
+            if !ray.may_touch(&bounds.mins, &bounds.maxs) {
+                continue;
+            }
+            if self.model_brushes(model).iter().any(|brush| brush.touches_ray(ray)) {
+                touched.push(entityidx as u32);
             }
         }
 
-        best
+        touched
     }
 }
 
@@ -60,52 +423,121 @@ pub struct MapFace {
 }
 
 #[derive(Copy, Clone, Debug)]
-pub struct MapVertex {
+pub struct SurfaceVertex {
     pub position: [f32; 3],
     pub texcoords: [f32; 2],
     pub lightmaptexcoords: [f32; 2],
     pub normal: [f32; 3]
 }
-implement_vertex!(MapVertex, position, texcoords, lightmaptexcoords, normal);
 
-pub struct GraphicsMap {
-    pub vertices: glium::VertexBuffer<MapVertex>,
-    pub indices: glium::IndexBuffer<u32>,
+/// A camera-facing sprite, like the flares around lights.
+#[derive(Debug)]
+pub struct MapBillboard {
+    pub position: [f32; 3],
+    pub texture: i32,
+}
+
+/// Everything needed to draw a map, short of actually uploading it anywhere.
+pub struct MapSurfaces {
+    pub vertices: Vec<SurfaceVertex>,
+    /// Triangle list.
+    pub indices: Vec<u32>,
     pub faces: Vec<MapFace>, 
-    pub textures: Vec<glium::Texture2d>,
-    pub lightmaps: Vec<glium::Texture2d>,
-    pub shaders: Vec<glium::Program>,
+    pub billboards: Vec<MapBillboard>,
+    /// Indices of faces of unknown types, which aren't in `faces`.
+    pub skipped_faces: Vec<u32>,
+    /// Texture names, without an extension.
+    pub textures: Vec<String>,
+    /// 128x128 RGB lightmaps.
+    pub lightmaps: Vec<Vec<Vec<(u8, u8, u8)>>>,
 }
 
 pub mod cast {
     use na;
+    use {Contents, SurfaceFlags};
 
     /// Secretly not a ray, it can have thickness to it.
     pub struct Ray {
         pub orig: na::Pnt3<f32>,
         pub dir: na::Vec3<f32>,
         pub halfextents: na::Vec3<f32>,
+        /// Only brushes with some of these contents get hit.
+        pub mask: Contents,
+    }
+    impl Ray {
+        /// A cheap check of whether the box, swept along the ray, could touch something
+        /// with the given bounds. False means it definitely can't.
+        pub fn may_touch(&self, mins: &na::Vec3<f32>, maxs: &na::Vec3<f32>) -> bool {
+            // Leave plenty of room for the epsilons brushes use.
+            let pad = self.halfextents + na::Vec3::new(1.0, 1.0, 1.0);
+            let start = self.orig.to_vec();
+            let end = start + self.dir;
+            let lo = na::Vec3::new(f32::min(start.x, end.x), f32::min(start.y, end.y), f32::min(start.z, end.z)) - pad;
+            let hi = na::Vec3::new(f32::max(start.x, end.x), f32::max(start.y, end.y), f32::max(start.z, end.z)) + pad;
+            lo.x <= maxs.x && lo.y <= maxs.y && lo.z <= maxs.z &&
+                hi.x >= mins.x && hi.y >= mins.y && hi.z >= mins.z
+        }
     }
 
+    /// What a cast ran into, a lot like Quake 3's `trace_t`.
+    ///
+    /// Casts that start inside a brush and get out without hitting anything still
+    /// produce a result, with `start_solid` set, a `toi` of 1 and no plane.
     #[derive(Copy, Clone,Debug, PartialEq)]
     pub struct CastResult {
-        /// Time of impact.
+        /// Time of impact, as a fraction of the ray.
         pub toi: f32,
-        /// Normal of the plane it hit. 
+        /// Where the center of the box was at the time of impact.
+        pub endpos: na::Pnt3<f32>,
+        /// Normal of the plane it hit. Zero if it's `all_solid`, or didn't hit anything.
         pub norm: na::Vec3<f32>,
+        /// Distance of the plane it hit from the origin, along `norm`.
+        pub dist: f32,
 
         /// Entity hit by the cast.
-        pub entity: Option<u32>
+        pub entity: Option<u32>,
+        /// Index of the brush that got hit, in `bsp::Tree::brushes`.
+        pub brush: Option<u32>,
+
+        /// The box started out inside a brush.
+        pub start_solid: bool,
+        /// The box never got out of the brush it started in, so `toi` is 0.
+        pub all_solid: bool,
+
+        /// Contents of the brush that got hit.
+        pub contents: Contents,
+        /// Flags of the brush side that got hit.
+        pub surface: SurfaceFlags,
     }
+    impl CastResult {
+        /// A hit on the plane with the given normal and distance, `toi` along `ray`.
+        pub fn new(ray: &Ray, toi: f32, norm: na::Vec3<f32>, dist: f32) -> CastResult {
+            CastResult {
+                toi: toi,
+                endpos: (ray.orig.to_vec() + ray.dir * toi).to_pnt(),
+                norm: norm,
+                dist: dist,
+                entity: None,
+                brush: None,
+                start_solid: false,
+                all_solid: false,
+                contents: Contents::empty(),
+                surface: SurfaceFlags::empty()
+            }
+        }
+    }
+    /// The earlier of two results. Either one starting out in something solid means both did.
     pub fn combine_results(a: Option<CastResult>, b: Option<CastResult>) -> Option<CastResult> {
         if let Some(a) = a {
             match b {
                 Some(b) => {
-                    if a.toi <= b.toi {
-                        Some(a)
+                    let start_solid = a.start_solid || b.start_solid;
+                    let first = if a.toi <= b.toi {
+                        a
                     } else {
-                        Some(b)
-                    }
+                        b
+                    };
+                    Some(CastResult { start_solid: start_solid, ..first })
                 },
                 None => Some(a)
             }
@@ -115,3 +547,247 @@ pub mod cast {
     }
 
 }
+
+#[cfg(test)]
+mod test {
+    use na;
+    use builder::MapBuilder;
+    use mover::MoverPath;
+    use cast::Ray;
+    use {
+        Entity,
+        EntityKind,
+        Contents,
+        CONTENTS_SOLID,
+        CONTENTS_WATER,
+        CONTENTS_PLAYERCLIP,
+        CONTENTS_TRIGGER,
+        MASK_PLAYERSOLID,
+        MASK_SHOT,
+        SURF_SLICK
+    };
+
+    fn pool_map() -> ::Map {
+        // A floor with a pool of water on top of it, and a trigger
+        // over the dry part.
+        MapBuilder::new()
+            .add_box(na::Vec3::new(-256.0, 0.0, -256.0), na::Vec3::new(256.0, 32.0, 256.0))
+            .add_box_with_contents(na::Vec3::new(-256.0, -64.0, -256.0), na::Vec3::new(0.0, 0.0, 256.0), CONTENTS_WATER)
+            .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Checkpoint),
+                              na::Vec3::new(64.0, -64.0, -64.0), na::Vec3::new(128.0, 0.0, 64.0))
+            .build()
+    }
+
+    #[test]
+    fn point_contents() {
+        let map = pool_map();
+        assert_eq!(map.point_contents(&na::Pnt3::new(0.0, 16.0, 0.0)).contents, CONTENTS_SOLID);
+        assert_eq!(map.point_contents(&na::Pnt3::new(-128.0, -32.0, 0.0)).contents, CONTENTS_WATER);
+        assert_eq!(map.point_contents(&na::Pnt3::new(128.0, -128.0, 0.0)).contents, Contents::empty());
+
+        let trigger = map.point_contents(&na::Pnt3::new(96.0, -32.0, 0.0));
+        assert_eq!(trigger.contents, CONTENTS_TRIGGER);
+        assert_eq!(trigger.entities, vec![1]);
+    }
+
+    #[test]
+    fn box_contents() {
+        let map = pool_map();
+        let halfextents = na::Vec3::new(8.0, 8.0, 8.0);
+
+        // Standing on the floor isn't being inside it.
+        let standing = map.box_contents(&na::Pnt3::new(200.0, -8.0, 0.0), &halfextents);
+        assert_eq!(standing.contents, Contents::empty());
+
+        // Half in the water, half in the floor.
+        let sunk = map.box_contents(&na::Pnt3::new(-128.0, 0.0, 0.0), &halfextents);
+        assert_eq!(sunk.contents, CONTENTS_SOLID | CONTENTS_WATER);
+
+        // Straddling the edge of the pool and the trigger.
+        let wading = map.box_contents(&na::Pnt3::new(60.0, -16.0, 0.0), &na::Vec3::new(64.0, 8.0, 8.0));
+        assert_eq!(wading.contents, CONTENTS_WATER | CONTENTS_TRIGGER);
+        assert_eq!(wading.entities, vec![1]);
+    }
+
+    #[test]
+    fn casts_respect_masks() {
+        // A clip brush in front of a slick wall, with a trigger in front of both.
+        let map = MapBuilder::new()
+            .add_box_with_contents(na::Vec3::new(64.0, -64.0, -64.0), na::Vec3::new(80.0, 64.0, 64.0), CONTENTS_PLAYERCLIP)
+            .add_box_with_surface(na::Vec3::new(128.0, -64.0, -64.0), na::Vec3::new(160.0, 64.0, 64.0), SURF_SLICK)
+            .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Checkpoint),
+                              na::Vec3::new(16.0, -64.0, -64.0), na::Vec3::new(32.0, 64.0, 64.0))
+            .build();
+        let ray = |mask| Ray {
+            orig: na::Pnt3::new(0.0, 0.0, 0.0),
+            dir: na::Vec3::new(256.0, 0.0, 0.0),
+            halfextents: na::Vec3::new(8.0, 8.0, 8.0),
+            mask: mask
+        };
+
+        let player = map.cast_ray(&ray(MASK_PLAYERSOLID)).unwrap();
+        assert_eq!(player.contents, CONTENTS_PLAYERCLIP);
+        assert!(player.toi < 0.25);
+
+        let shot = map.cast_ray(&ray(MASK_SHOT)).unwrap();
+        assert_eq!(shot.contents, CONTENTS_SOLID);
+        assert_eq!(shot.surface, SURF_SLICK);
+        assert!(shot.toi > 0.45);
+
+        assert!(map.cast_ray(&ray(CONTENTS_WATER)).is_none());
+    }
+
+    #[test]
+    fn triggers_arent_solid() {
+        let map = MapBuilder::new()
+            .add_brush_entity(Entity::new("trigger_hurt", EntityKind::OutOfBounds),
+                              na::Vec3::new(16.0, -64.0, -64.0), na::Vec3::new(32.0, 64.0, 64.0))
+            .add_brush_entity(Entity::new("trigger_multiple", EntityKind::Goal),
+                              na::Vec3::new(64.0, -64.0, -64.0), na::Vec3::new(80.0, 64.0, 64.0))
+            .build();
+        let ray = Ray {
+            orig: na::Pnt3::new(0.0, 0.0, 0.0),
+            dir: na::Vec3::new(128.0, 0.0, 0.0),
+            halfextents: na::Vec3::new(8.0, 8.0, 8.0),
+            mask: Contents::all()
+        };
+        assert!(map.cast_ray(&ray).is_none());
+        assert_eq!(map.touch_triggers(&ray), vec![1, 2]);
+    }
+
+    #[test]
+    fn casts_against_movers() {
+        let mut map = MapBuilder::new()
+            .add_mover(na::Vec3::new(-16.0, -16.0, -16.0), na::Vec3::new(16.0, 16.0, 16.0), MoverPath {
+                stops: vec![na::zero(), na::Vec3::new(64.0, 0.0, 0.0)],
+                speed: 64.0,
+                wait: 0.0,
+                triggered: false
+            })
+            .build();
+        let ray = Ray {
+            orig: na::Pnt3::new(-128.0, 0.0, 0.0),
+            dir: na::Vec3::new(256.0, 0.0, 0.0),
+            halfextents: na::zero(),
+            mask: MASK_PLAYERSOLID
+        };
+        let at_start = map.cast_ray(&ray).unwrap();
+        assert_eq!(at_start.entity, Some(1));
+
+        map.update_movers(1.0);
+        assert_eq!(map.movers[0].delta, na::Vec3::new(64.0, 0.0, 0.0));
+        let moved = map.cast_ray(&ray).unwrap();
+        assert!(na::approx_eq_eps(&(moved.toi - at_start.toi), &0.25, &0.01));
+
+        // Leaving the mover from inside it.
+        let leaving = map.cast_ray(&Ray {
+            orig: na::Pnt3::new(64.0, 0.0, 0.0),
+            dir: na::Vec3::new(0.0, -64.0, 0.0),
+            halfextents: na::zero(),
+            mask: MASK_PLAYERSOLID
+        }).unwrap();
+        assert!(leaving.start_solid && !leaving.all_solid);
+        assert_eq!(leaving.entity, Some(1));
+
+        assert_eq!(map.point_contents(&na::Pnt3::new(64.0, 0.0, 0.0)).contents, CONTENTS_SOLID);
+        assert_eq!(map.point_contents(&na::Pnt3::new(0.0, 0.0, 0.0)).contents, Contents::empty());
+    }
+
+    #[test]
+    fn doors_wait_to_be_touched() {
+        let mut map = MapBuilder::new()
+            .add_mover(na::Vec3::new(-16.0, -16.0, -16.0), na::Vec3::new(16.0, 16.0, 16.0), MoverPath {
+                stops: vec![na::zero(), na::Vec3::new(0.0, -64.0, 0.0)],
+                speed: 64.0,
+                wait: 1.0,
+                triggered: true
+            })
+            .build();
+        let halfextents = na::Vec3::new(8.0, 8.0, 8.0);
+
+        // Nobody's near it, so it stays shut.
+        map.trigger_movers(0.0, &na::Pnt3::new(64.0, 0.0, 0.0), &halfextents);
+        map.update_movers(5.0);
+        assert_eq!(map.movers[0].offset, na::zero());
+
+        // Standing right next to it sets it off: it opens straight away, waits, and shuts.
+        map.trigger_movers(5.0, &na::Pnt3::new(24.5, 0.0, 0.0), &halfextents);
+        map.update_movers(5.5);
+        assert!(na::approx_eq(&map.movers[0].offset, &na::Vec3::new(0.0, -32.0, 0.0)));
+        map.update_movers(6.5);
+        assert!(na::approx_eq(&map.movers[0].offset, &na::Vec3::new(0.0, -64.0, 0.0)));
+        map.update_movers(7.5);
+        assert!(na::approx_eq(&map.movers[0].offset, &na::Vec3::new(0.0, -32.0, 0.0)));
+        map.update_movers(8.5);
+        assert_eq!(map.movers[0].offset, na::zero());
+        assert_eq!(map.movers[0].triggered_at, None);
+        map.update_movers(9.0);
+        assert_eq!(map.movers[0].offset, na::zero());
+    }
+
+    #[test]
+    fn batched_casts_match_single() {
+        let mut map = MapBuilder::new()
+            .add_box(na::Vec3::new(-256.0, 0.0, -256.0), na::Vec3::new(256.0, 32.0, 256.0))
+            .add_box_with_contents(na::Vec3::new(-256.0, -64.0, -256.0), na::Vec3::new(0.0, 0.0, 256.0), CONTENTS_WATER)
+            .add_box_with_contents(na::Vec3::new(64.0, -128.0, -64.0), na::Vec3::new(96.0, 0.0, 64.0), CONTENTS_PLAYERCLIP)
+            .add_mover(na::Vec3::new(-16.0, -48.0, -16.0), na::Vec3::new(16.0, -16.0, 16.0), MoverPath {
+                stops: vec![na::zero(), na::Vec3::new(0.0, 0.0, 128.0)],
+                speed: 64.0,
+                wait: 0.0,
+                triggered: false
+            })
+            .build();
+        map.update_movers(0.5);
+
+        // Enough rays to get split across threads if that's enabled.
+        let masks = [MASK_PLAYERSOLID, MASK_SHOT, CONTENTS_WATER];
+        let rays: Vec<Ray> = (0..1000).map(|i| {
+            let f = i as f32;
+            Ray {
+                orig: na::Pnt3::new((f * 37.0) % 512.0 - 256.0, (f * 11.0) % 160.0 - 128.0, (f * 53.0) % 512.0 - 256.0),
+                dir: na::Vec3::new((f * 7.0) % 256.0 - 128.0, (f * 13.0) % 256.0 - 128.0, (f * 29.0) % 256.0 - 128.0),
+                halfextents: if i % 2 == 0 { na::zero() } else { na::Vec3::new(8.0, 12.0, 8.0) },
+                mask: masks[i % masks.len()]
+            }
+        }).collect();
+
+        let batched = map.cast_rays(&rays);
+        assert_eq!(batched.len(), rays.len());
+        for (ray, result) in rays.iter().zip(batched.iter()) {
+            assert_eq!(*result, map.cast_ray(ray));
+        }
+        assert!(batched.iter().any(|result| result.is_some()));
+        assert!(batched.iter().any(|result| result.is_none()));
+        assert!(map.cast_rays(&[]).is_empty());
+    }
+
+    #[test]
+    fn unstick() {
+        // A floor with its top at y = 0, and a wall from x = 32 on.
+        let map = MapBuilder::new()
+            .add_box(na::Vec3::new(-256.0, 0.0, -256.0), na::Vec3::new(256.0, 64.0, 256.0))
+            .add_box(na::Vec3::new(32.0, -256.0, -256.0), na::Vec3::new(64.0, 0.0, 256.0))
+            .add_box_with_contents(na::Vec3::new(-256.0, -256.0, -256.0), na::Vec3::new(-128.0, 0.0, 256.0), CONTENTS_WATER)
+            .build();
+        let halfextents = na::Vec3::new(8.0, 8.0, 8.0);
+        let unstick = |x, y| map.unstick(&na::Pnt3::new(x, y, 0.0), &halfextents, MASK_PLAYERSOLID).unwrap();
+
+        // Not stuck at all.
+        assert_eq!(unstick(0.0, -16.0), na::zero());
+        // Only liquids in the way.
+        assert_eq!(unstick(-192.0, -16.0), na::zero());
+
+        // Sunk 4 units into the floor, so straight up is shortest.
+        let offset = unstick(0.0, -4.0);
+        assert!(offset.x == 0.0 && offset.z == 0.0);
+        assert!(offset.y < -4.0 && offset.y > -4.5);
+
+        // In the corner between the floor and the wall, it has to get out of both.
+        let offset = unstick(28.0, -6.0);
+        assert!(offset.x < -4.0 && offset.x > -4.5);
+        assert!(offset.y < -2.0 && offset.y > -2.5);
+        let pos = na::Pnt3::new(28.0, -6.0, 0.0) + offset;
+        assert_eq!(map.box_contents(&pos, &halfextents).contents, Contents::empty());
+    }
+}
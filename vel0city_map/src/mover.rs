@@ -0,0 +1,200 @@
+//! Brush entities that move, like doors, platforms and trains.
+//!
+//! Trains endlessly go around their path, so where they are only depends on the
+//! game time. Doors and plats rest at the start of their path until a player
+//! touches them, and then go around it once.
+
+use na;
+use {
+    Entity,
+    Model
+};
+
+/// Where a mover goes, as offsets from where its brushes were compiled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoverPath {
+    /// Visited in order, after the last one the mover heads back to the first.
+    pub stops: Vec<na::Vec3<f32>>,
+    /// In units per second.
+    pub speed: f32,
+    /// How long to stay at each stop, in seconds.
+    pub wait: f32,
+    /// Whether the mover waits at the first stop until it's triggered, instead of
+    /// going around forever.
+    pub triggered: bool,
+}
+impl MoverPath {
+    fn legs(&self) -> Vec<(na::Vec3<f32>, na::Vec3<f32>, f32)> {
+        (0..self.stops.len()).map(|i| {
+            let from = self.stops[i];
+            let to = self.stops[(i + 1) % self.stops.len()];
+            (from, to, na::norm(&(to - from)) / self.speed)
+        }).collect()
+    }
+
+    /// How long it takes to go around the whole path once.
+    pub fn period(&self) -> f32 {
+        self.legs().iter().fold(0.0, |total, &(_, _, duration)| total + self.wait + duration)
+    }
+
+    /// Where the mover is at the given time.
+    pub fn offset_at(&self, time: f32) -> na::Vec3<f32> {
+        let period = self.period();
+        if self.stops.len() < 2 || !(period > 0.0) {
+            return self.stops.first().cloned().unwrap_or(na::zero());
+        }
+
+        let mut t = time % period;
+        for (from, to, duration) in self.legs() {
+            if t < self.wait {
+                return from;
+            }
+            t -= self.wait;
+            if t < duration {
+                return from + (to - from) * (t / duration);
+            }
+            t -= duration;
+        }
+        self.stops[0]
+    }
+
+    /// Where a triggered mover is, `time` seconds after it was set off, or `None` once
+    /// it's back at the first stop. It leaves right away rather than waiting there first.
+    pub fn triggered_offset_at(&self, time: f32) -> Option<na::Vec3<f32>> {
+        let time = time + self.wait;
+        if time < self.period() {
+            Some(self.offset_at(time))
+        } else {
+            None
+        }
+    }
+}
+
+/// A moving brush entity's current state.
+#[derive(Clone, Debug)]
+pub struct Mover {
+    pub entity: u32,
+    pub offset: na::Vec3<f32>,
+    /// How far it moved during the last update.
+    pub delta: na::Vec3<f32>,
+    /// When a triggered mover was set off, if it's still on its way around.
+    pub triggered_at: Option<f32>,
+}
+
+/// A mover for every entity with a path, at the start of its path.
+pub fn find_movers(entities: &[Entity]) -> Vec<Mover> {
+    entities.iter().enumerate().filter_map(|(idx, ent)| {
+        ent.path.as_ref().map(|path| Mover {
+            entity: idx as u32,
+            offset: path.offset_at(0.0),
+            delta: na::zero(),
+            triggered_at: None
+        })
+    }).collect()
+}
+
+fn float_property(ent: &Entity, key: &str, default: f32) -> f32 {
+    ent.properties.get(key)
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Turns a Quake 3 mover "angle" into a direction. -1 is up and -2 is down.
+fn angle_to_dir(angle: f32) -> na::Vec3<f32> {
+    if angle == -1.0 {
+        na::Vec3::new(0.0, -1.0, 0.0)
+    } else if angle == -2.0 {
+        na::Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        let rad = angle * ::std::f32::consts::PI / 180.0;
+        na::Vec3::new(rad.cos(), 0.0, rad.sin())
+    }
+}
+
+/// Works out the path of a func_door, func_plat or func_train, using the same
+/// keys and defaults as Quake 3.
+///
+/// Trains go through the chain of path_corners starting at their target,
+/// with the bottom corner of the model's bounding box at each one.
+/// Doors and plats get a path between their two positions, which they go
+/// through once each time they're triggered; see `EntityKind::Mover`.
+pub fn mover_path(ent: &Entity, model: &Model, entities: &[Entity]) -> Option<MoverPath> {
+    let size = model.maxs - model.mins;
+    let lip = float_property(ent, "lip", 8.0);
+    match &ent.classname[..] {
+        "func_door" => {
+            let dir = angle_to_dir(ent.angle);
+            let dist = na::abs(&na::dot(&size, &dir)) - lip;
+            Some(MoverPath {
+                stops: vec![na::zero(), dir * dist],
+                speed: float_property(ent, "speed", 400.0),
+                wait: float_property(ent, "wait", 2.0),
+                triggered: true
+            })
+        },
+        "func_plat" => {
+            let height = float_property(ent, "height", size.y - lip);
+            Some(MoverPath {
+                // Plats rest at the bottom. Remember that +y is down.
+                stops: vec![na::Vec3::new(0.0, height, 0.0), na::zero()],
+                speed: float_property(ent, "speed", 200.0),
+                wait: 1.0,
+                triggered: true
+            })
+        },
+        "func_train" => {
+            // Quake 3's mins, which is at the bottom since -y is up.
+            let corner = na::Vec3::new(model.mins.x, model.maxs.y, model.mins.z);
+            let mut stops = vec![];
+            let mut visited: Vec<usize> = vec![];
+            let mut target = ent.target.clone();
+            while let Some(name) = target {
+                let next = entities.iter().position(|e| {
+                    e.classname == "path_corner" && e.targetname.as_ref() == Some(&name)
+                });
+                let next = match next {
+                    Some(next) if !visited.contains(&next) => next,
+                    _ => break
+                };
+                visited.push(next);
+                if let Some(origin) = entities[next].origin {
+                    stops.push(origin.to_vec() - corner);
+                }
+                target = entities[next].target.clone();
+            }
+            if stops.is_empty() {
+                return None;
+            }
+            Some(MoverPath {
+                stops: stops,
+                speed: float_property(ent, "speed", 100.0),
+                wait: 0.0,
+                triggered: false
+            })
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use super::MoverPath;
+
+    #[test]
+    fn follows_path() {
+        let path = MoverPath {
+            stops: vec![na::zero(), na::Vec3::new(0.0, -100.0, 0.0)],
+            speed: 50.0,
+            wait: 1.0,
+            triggered: false
+        };
+        assert_eq!(path.period(), 6.0);
+        assert_eq!(path.offset_at(0.5), na::zero());
+        assert!(na::approx_eq(&path.offset_at(2.0), &na::Vec3::new(0.0, -50.0, 0.0)));
+        assert!(na::approx_eq(&path.offset_at(3.5), &na::Vec3::new(0.0, -100.0, 0.0)));
+        assert!(na::approx_eq(&path.offset_at(5.0), &na::Vec3::new(0.0, -50.0, 0.0)));
+        // And around again.
+        assert!(na::approx_eq(&path.offset_at(8.0), &na::Vec3::new(0.0, -50.0, 0.0)));
+    }
+}
@@ -0,0 +1,147 @@
+use std::ops::{Add, Mul};
+use na;
+use bsp;
+
+/// How far behind its surface the brushes made for a patch's triangles reach.
+pub const PATCH_THICKNESS: f32 = 4.0;
+
+fn bezier<T>(p0: T, p1: T, p2: T, t: f32) -> T
+    where T: Copy + Add<T, Output=T> + Mul<f32, Output=T> {
+    let s = 1.0 - t;
+    p0 * (s * s) + p1 * (2.0 * s * t) + p2 * (t * t)
+}
+
+/// Tessellates a Quake 3 patch: a `width` by `height` grid of control points
+/// making up one or more 3x3 biquadratic Bezier patches, each of which
+/// gets split into `level` by `level` quads.
+///
+/// Returns the new vertices, and a triangle list indexing into them
+/// that's wound the same way Quake 3 winds its patches.
+pub fn tessellate<T>(controls: &[T], width: usize, height: usize, level: u32) -> (Vec<T>, Vec<u32>)
+    where T: Copy + Add<T, Output=T> + Mul<f32, Output=T> {
+    let level = if level < 1 { 1 } else { level as usize };
+    let n = level + 1;
+
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    for py in 0..(height - 1) / 2 {
+        for px in 0..(width - 1) / 2 {
+            let base = vertices.len() as u32;
+            let control = |x: usize, y: usize| controls[(py * 2 + y) * width + px * 2 + x];
+
+            for row in 0..n {
+                let v = row as f32 / level as f32;
+                let c0 = bezier(control(0, 0), control(0, 1), control(0, 2), v);
+                let c1 = bezier(control(1, 0), control(1, 1), control(1, 2), v);
+                let c2 = bezier(control(2, 0), control(2, 1), control(2, 2), v);
+                for col in 0..n {
+                    let u = col as f32 / level as f32;
+                    vertices.push(bezier(c0, c1, c2, u));
+                }
+            }
+
+            for row in 0..level {
+                for col in 0..level {
+                    let i = base + (row * n + col) as u32;
+                    let below = i + n as u32;
+                    indices.push(i);
+                    indices.push(below);
+                    indices.push(i + 1);
+
+                    indices.push(below);
+                    indices.push(below + 1);
+                    indices.push(i + 1);
+                }
+            }
+        }
+    }
+
+    (vertices, indices)
+}
+
+fn side(norm: na::Vec3<f32>, dist: f32, contents: i32, flags: i32) -> bsp::BrushSide {
+    bsp::BrushSide {
+        plane: bsp::Plane {
+            norm: norm,
+            dist: dist
+        },
+        contents: contents,
+        flags: flags
+    }
+}
+
+/// Builds a thin brush just behind a triangle of a patch, so that it can be collided with.
+/// `up` is roughly the direction the front of the triangle faces.
+/// Returns None for degenerate triangles.
+pub fn triangle_brush(tri: [na::Vec3<f32>; 3], up: na::Vec3<f32>, contents: i32, flags: i32) -> Option<bsp::Brush> {
+    let (a, b, c) = (tri[0], tri[1], tri[2]);
+
+    let cross = na::cross(&(b - a), &(c - a));
+    if na::norm(&cross) < 0.0001 {
+        return None;
+    }
+    let mut norm = na::normalize(&cross);
+    if na::dot(&norm, &up) < 0.0 {
+        norm = -norm;
+    }
+
+    let mut sides = vec![
+        side(norm, na::dot(&norm, &a), contents, flags),
+        side(-norm, -(na::dot(&norm, &a) - PATCH_THICKNESS), contents, flags),
+    ];
+
+    for &(p, q, r) in [(a, b, c), (b, c, a), (c, a, b)].iter() {
+        let mut edgenorm = na::normalize(&na::cross(&(q - p), &norm));
+        if na::dot(&edgenorm, &(r - p)) > 0.0 {
+            edgenorm = -edgenorm;
+        }
+        sides.push(side(edgenorm, na::dot(&edgenorm, &p), contents, flags));
+    }
+
+    // Axial bevels, so that boxes don't catch on the edge planes
+    // far away from the triangle itself.
+    let back = norm * -PATCH_THICKNESS;
+    let corners = [a, b, c, a + back, b + back, c + back];
+    let axes = [
+        na::Vec3::new(1.0, 0.0, 0.0),
+        na::Vec3::new(0.0, 1.0, 0.0),
+        na::Vec3::new(0.0, 0.0, 1.0),
+    ];
+    for axis in axes.iter() {
+        let first = na::dot(axis, &corners[0]);
+        let max = corners.iter().fold(first, |m, p| f32::max(m, na::dot(axis, p)));
+        let min = corners.iter().fold(first, |m, p| f32::min(m, na::dot(axis, p)));
+        sides.push(side(*axis, max, contents, flags));
+        sides.push(side(-*axis, -min, contents, flags));
+    }
+
+    Some(bsp::Brush::new(sides, contents))
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use super::tessellate;
+
+    #[test]
+    fn tessellates_flat_patch() {
+        // Two patches side by side, on the y = 0 plane.
+        let mut controls = vec![];
+        for z in 0..3 {
+            for x in 0..5 {
+                controls.push(na::Vec3::new(x as f32 * 8.0, 0.0, z as f32 * 8.0));
+            }
+        }
+
+        let (vertices, indices) = tessellate(&controls, 5, 3, 4);
+        assert_eq!(vertices.len(), 2 * 5 * 5);
+        assert_eq!(indices.len(), 2 * 4 * 4 * 6);
+        assert!(indices.iter().all(|&i| (i as usize) < vertices.len()));
+        assert!(vertices.iter().all(|v| v.y == 0.0));
+
+        // The corners of a patch are interpolated exactly.
+        assert!(na::approx_eq(&vertices[0], &controls[0]));
+        assert!(na::approx_eq(&vertices[24], &controls[12]));
+        assert!(na::approx_eq(&vertices[49], &controls[14]));
+    }
+}
@@ -1,25 +1,58 @@
 #![allow(dead_code, unused_variables)]
 use bsp;
+use mover;
+use patch;
 use byteorder::{self, LittleEndian, ReadBytesExt};
 use std::io::{Cursor, SeekFrom, Seek};
 use std;
-use std::borrow::ToOwned;
-use glium;
-use image;
+use std::collections::HashMap;
+use std::ops::{Add, Mul};
 use na;
 use { 
     Map,
     Model,
-    GraphicsMap,
-    MapVertex,
-    MapFace
+    Entity,
+    EntityKind,
+    SpawnPoint,
+    MapSurfaces,
+    SurfaceVertex,
+    MapFace,
+    MapBillboard,
+    MASK_PLAYERSOLID,
+    SURF_NONSOLID
 };
-use vel0city_base::assets;
 
 #[derive(Debug)]
 pub enum BspError {
     ByteOrderError(byteorder::Error),
     NotUtf8(std::str::Utf8Error),
+    BadEntityLump(&'static str),
+    /// Doesn't start with "IBSP".
+    BadMagic,
+    /// Only Quake 3 (46) and Quake Live (47) maps are supported.
+    UnsupportedVersion(u32),
+    /// A lump extends past the end of the file (or into the header).
+    LumpOutOfBounds {
+        lump: &'static str,
+        offset: u32,
+        len: u32
+    },
+    /// A lump's length isn't a multiple of the size of its elements.
+    BadLumpSize {
+        lump: &'static str,
+        len: u32
+    },
+    /// Something refers to an element that doesn't exist.
+    BadIndex {
+        lump: &'static str,
+        index: i64
+    },
+    /// A patch's control points don't make up a grid of 3x3 patches.
+    BadPatchSize {
+        face: u32,
+        width: i32,
+        height: i32
+    },
 }
 impl std::convert::From<byteorder::Error> for BspError {
     fn from(e: byteorder::Error) -> BspError {
@@ -31,21 +64,48 @@ impl std::convert::From<std::str::Utf8Error> for BspError {
         BspError::NotUtf8(e)
     }
 }
-pub fn import_entities(data: &[u8]) -> Result<String, BspError> {
+pub fn import_entities(data: &[u8]) -> Result<Vec<Entity>, BspError> {
     let directory = try!(read_directory(data));
-    Ok(try!(std::str::from_utf8(directory.entities)).to_owned())
+    let lump = try!(std::str::from_utf8(directory.entities));
+    parse_entities(lump.trim_right_matches('\0'))
 }
 
 pub fn import(data: &[u8]) -> Result<Map, BspError> {
     let directory = try!(read_directory(data));
     let planes = try!(read_planes(directory.planes));
-    let nodes = try!(read_nodes(directory.nodes, &planes));
-    let leaves = try!(read_leaves(directory.leaves)); 
     let textures = try!(read_textures(directory.textures));
     let brushsides = try!(read_brushsides(directory.brushsides, &planes, &textures));
-    let brushes = try!(read_brushes(directory.brushes, &brushsides));
-    let leafbrushes = try!(read_leafbrushes(directory.leafbrushes));
-    let models = try!(read_models(directory.models));
+    let brushes = try!(read_brushes(directory.brushes, &brushsides, &textures));
+    let leafbrushes = try!(read_leafbrushes(directory.leafbrushes, brushes.len()));
+    let leaves = try!(read_leaves(directory.leaves, leafbrushes.len())); 
+    let nodes = try!(read_nodes(directory.nodes, &planes, leaves.len()));
+    let models = try!(read_models(directory.models, brushes.len()));
+    let faces = try!(read_faces(directory.faces));
+    let vertices = try!(read_vertices(directory.vertices));
+    let leaffaces = try!(read_leaffaces(directory.leaffaces, faces.len()));
+    let leaf_faces = try!(read_leaf_faces(directory.leaves, leaffaces.len()));
+    let mut entities = try!(import_entities(data));
+    for ent in &entities {
+        if let Some(model) = ent.model {
+            try!(check_index(model as i64, models.len(), "models"));
+        }
+    }
+    let paths: Vec<Option<mover::MoverPath>> = entities.iter().map(|ent| {
+        match (ent.kind, ent.model) {
+            (EntityKind::Mover, Some(model)) => mover::mover_path(ent, &models[model as usize], &entities),
+            _ => None
+        }
+    }).collect();
+    for (ent, path) in entities.iter_mut().zip(paths.into_iter()) {
+        ent.path = path;
+    }
+    let spawnpoints = find_spawnpoints(&entities);
+
+    let mut brushes = brushes;
+    let mut leaves = leaves;
+    let mut leafbrushes = leafbrushes;
+    try!(add_patch_brushes(&faces, &vertices, &textures, &leaffaces, &leaf_faces,
+                           &mut brushes, &mut leaves, &mut leafbrushes));
 
     Ok(Map {
         bsp: bsp::Tree {
@@ -55,27 +115,131 @@ pub fn import(data: &[u8]) -> Result<Map, BspError> {
             inodes: nodes,
         },
         models: models, 
-        entities: vec![
-            ::Entity { model: 1, kind: ::EntityKind::OutOfBounds },
-            ::Entity { model: 2, kind: ::EntityKind::Goal },
-        ]
+        movers: mover::find_movers(&entities),
+        entities: entities,
+        spawnpoints: spawnpoints,
     })
 }
 
-pub fn import_graphics_model(data: &[u8], display: &glium::Display) -> Result<GraphicsMap, BspError> {
+/// How finely patches are tessellated for collision.
+const COLLISION_PATCH_LEVEL: u32 = 4;
+
+/// Gives every solid patch a set of thin brushes following its surface,
+/// and adds those to every leaf the patch is in.
+fn add_patch_brushes(faces: &[Face],
+                     vertices: &[Vertex],
+                     textures: &[Texture],
+                     leaffaces: &[u32],
+                     leaf_faces: &[std::ops::Range<usize>],
+                     brushes: &mut Vec<bsp::Brush>,
+                     leaves: &mut Vec<bsp::Leaf>,
+                     leafbrushes: &mut Vec<u32>) -> Result<(), BspError> {
+    let mut face_brushes = vec![];
+    for (faceidx, face) in faces.iter().enumerate() {
+        let start = brushes.len() as u32;
+        if face.kind == FACE_PATCH {
+            let controls = try!(patch_controls(faceidx, face, vertices));
+            let tex = &textures[try!(check_index(face.texture as i64, textures.len(), "textures"))];
+            let collides = tex.contents as u32 & MASK_PLAYERSOLID.bits() != 0 &&
+                tex.flags as u32 & SURF_NONSOLID.bits() == 0;
+            if collides {
+                let (patchverts, indices) = patch::tessellate(controls, face.size.0 as usize, face.size.1 as usize, COLLISION_PATCH_LEVEL);
+                for tri in indices.chunks(3) {
+                    let (a, b, c) = (&patchverts[tri[0] as usize], &patchverts[tri[1] as usize], &patchverts[tri[2] as usize]);
+                    let up = a.engine_normal() + b.engine_normal() + c.engine_normal();
+                    let brush = patch::triangle_brush([a.engine_position(), b.engine_position(), c.engine_position()],
+                                                      up,
+                                                      tex.contents,
+                                                      tex.flags);
+                    if let Some(brush) = brush {
+                        brushes.push(brush);
+                    }
+                }
+            }
+        }
+        face_brushes.push(start..brushes.len() as u32);
+    }
+
+    let mut new_leafbrushes = vec![];
+    for (leaf, faces) in leaves.iter_mut().zip(leaf_faces.iter()) {
+        let start = new_leafbrushes.len();
+        new_leafbrushes.extend(leafbrushes[leaf.leafbrush as usize..(leaf.leafbrush + leaf.n_leafbrushes) as usize].iter().cloned());
+        for &face in &leaffaces[faces.clone()] {
+            new_leafbrushes.extend(face_brushes[face as usize].clone());
+        }
+        leaf.leafbrush = start as i32;
+        leaf.n_leafbrushes = (new_leafbrushes.len() - start) as i32;
+    }
+    *leafbrushes = new_leafbrushes;
+
+    Ok(())
+}
+
+/// Validates a patch face, returning its control points.
+fn patch_controls<'a>(faceidx: usize, face: &Face, vertices: &'a [Vertex]) -> Result<&'a [Vertex], BspError> {
+    let (width, height) = face.size;
+    if width < 3 || height < 3 || width % 2 == 0 || height % 2 == 0 ||
+        width as i64 * height as i64 != face.n_vertexes as i64 {
+        return Err(BspError::BadPatchSize { face: faceidx as u32, width: width, height: height });
+    }
+    let range = try!(check_range(face.vertex as i64, face.n_vertexes as i64, vertices.len(), "vertices"));
+    Ok(&vertices[range])
+}
+
+/// Imports everything needed to draw the map, with patches tessellated to the given level.
+pub fn import_surfaces(data: &[u8], patch_level: u32) -> Result<MapSurfaces, BspError> {
     let directory = try!(read_directory(data));
     let faces = try!(read_faces(directory.faces));
-    let vertices = try!(read_vertices(directory.vertices));
+    let mut vertices = try!(read_vertices(directory.vertices));
     let meshverts = try!(read_meshverts(directory.meshverts));
     let textures = try!(read_textures(directory.textures));
     let lightmaps = try!(read_lightmaps(directory.lightmaps));
 
     let mut indices = vec![];
     let mut fixed_faces = vec![];
-    for face in faces {
+    let mut patch_vertices = vec![];
+    let mut billboards = vec![];
+    let mut skipped_faces = vec![];
+    for (faceidx, face) in faces.into_iter().enumerate() {
+        try!(check_index(face.texture as i64, textures.len(), "textures"));
+        if face.lightmap >= 0 {
+            try!(check_index(face.lightmap as i64, lightmaps.len(), "lightmaps"));
+        }
+
         let index_start = indices.len();
-        for meshvert in &meshverts[face.meshvert as usize.. (face.meshvert + face.n_meshverts) as usize] {
-            indices.push(face.vertex as u32 + *meshvert);
+        match face.kind {
+            FACE_POLYGON | FACE_MESH => {
+                let face_vertices = try!(check_range(face.vertex as i64, face.n_vertexes as i64, vertices.len(), "vertices"));
+                let face_meshverts = try!(check_range(face.meshvert as i64, face.n_meshverts as i64, meshverts.len(), "meshverts"));
+
+                for &meshvert in &meshverts[face_meshverts] {
+                    let vertex = try!(check_index(face.vertex as i64 + meshvert as i64, face_vertices.end, "vertices"));
+                    indices.push(vertex as u32);
+                }
+            },
+            FACE_PATCH => {
+                let (patchverts, patchindices) = {
+                    let controls = try!(patch_controls(faceidx, &face, &vertices));
+                    patch::tessellate(controls, face.size.0 as usize, face.size.1 as usize, patch_level)
+                };
+                // Tessellated vertices go after all of the map's own.
+                let base = vertices.len() + patch_vertices.len();
+                indices.extend(patchindices.into_iter().map(|i| base as u32 + i));
+                patch_vertices.extend(patchverts.into_iter());
+            },
+            FACE_BILLBOARD => {
+                // These have no geometry of their own, they're turned into sprites at draw time.
+                billboards.push(MapBillboard {
+                    position: [face.origin.x, -face.origin.z, face.origin.y],
+                    texture: face.texture,
+                });
+                continue;
+            },
+            _ => {
+                // Nothing we know how to draw, like the foliage of later id Tech 3 games.
+                skipped_faces.push(faceidx as u32);
+                continue;
+            }
         }
         let index_end = indices.len();
 
@@ -89,17 +253,15 @@ pub fn import_graphics_model(data: &[u8], display: &glium::Display) -> Result<Gr
 
 
 
-    let loaded_textures = textures.iter().map(|tex| {
-        let contents = assets::load_bin_asset(&(tex.name.clone() + ".png")).unwrap_or_else(|_| assets::load_bin_asset("textures/radiant/notex.png").unwrap());
-        let image = image::load(::std::io::Cursor::new(contents), image::PNG).unwrap();
-        let texture = glium::Texture2d::new(display, image);
-        texture
-    }).collect();
-    let loaded_lightmaps = lightmaps.into_iter().map(|lm|  
-                                                     glium::Texture2d::new(display, lm.data)
-                                                    ).collect();
-    let loaded_vertices = vertices.iter().map(|vert| {
-        MapVertex {
+    vertices.extend(patch_vertices.into_iter().map(|mut vert| {
+        // Interpolated normals need not be unit length.
+        if !na::approx_eq(&na::norm(&vert.normal), &0.0) {
+            vert.normal = na::normalize(&vert.normal);
+        }
+        vert
+    }));
+    let converted_vertices = vertices.iter().map(|vert| {
+        SurfaceVertex {
             position: [vert.position.x, -1.0 * vert.position.z, vert.position.y],
             texcoords: [1.0 - vert.texcoords.x, 1.0 - vert.texcoords.y],
             lightmaptexcoords: [vert.lightmaptexcoords.x, vert.lightmaptexcoords.y],
@@ -107,29 +269,247 @@ pub fn import_graphics_model(data: &[u8], display: &glium::Display) -> Result<Gr
         }
     }).collect::<Vec<_>>();
 
-    let main_program = glium::Program::from_source(
-        display,
-        &assets::load_str_asset("shaders/prepass/vertex.glsl").unwrap(),
-        &assets::load_str_asset("shaders/prepass/fragment.glsl").unwrap(),
-        None
-        ).unwrap();
-
-    Ok(GraphicsMap {
-        vertices: glium::VertexBuffer::new(display, loaded_vertices),
-        indices: glium::IndexBuffer::new(display, glium::index::PrimitiveType::TrianglesList, indices),
-        shaders: vec![main_program],
-        textures: loaded_textures,
-        lightmaps: loaded_lightmaps,
+    Ok(MapSurfaces {
+        vertices: converted_vertices,
+        indices: indices,
         faces: fixed_faces,
+        billboards: billboards,
+        skipped_faces: skipped_faces,
+        textures: textures.into_iter().map(|tex| tex.name).collect(),
+        lightmaps: lightmaps.into_iter().map(|lm| lm.data).collect(),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Str(String),
+}
+
+/// Splits an entity lump into braces and (possibly quoted) strings,
+/// skipping whitespace and // comments.
+fn tokenize_entities(text: &str) -> Result<Vec<Token>, BspError> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break
+        };
+        match c {
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => string.push(c),
+                        None => return Err(BspError::BadEntityLump("unterminated string"))
+                    }
+                }
+                tokens.push(Token::Str(string));
+            },
+            '/' if chars.peek() == Some(&'/') => {
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            },
+            c if c.is_whitespace() => (),
+            c => {
+                let mut string = String::new();
+                string.push(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '{' || c == '}' || c == '"' {
+                        break;
+                    }
+                    string.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Str(string));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parses Quake 3 entity syntax, i.e. a list of
+/// `{ "key" "value" ... }` blocks, into typed entities.
+pub fn parse_entities(text: &str) -> Result<Vec<Entity>, BspError> {
+    let tokens = try!(tokenize_entities(text));
+    let mut tokens = tokens.into_iter();
+
+    let mut entities = vec![];
+    loop {
+        match tokens.next() {
+            Some(Token::Open) => (),
+            Some(_) => return Err(BspError::BadEntityLump("expected {")),
+            None => break
+        }
+
+        let mut properties = HashMap::new();
+        loop {
+            let key = match tokens.next() {
+                Some(Token::Close) => break,
+                Some(Token::Str(key)) => key,
+                Some(Token::Open) => return Err(BspError::BadEntityLump("unexpected {")),
+                None => return Err(BspError::BadEntityLump("unterminated entity"))
+            };
+            let value = match tokens.next() {
+                Some(Token::Str(value)) => value,
+                _ => return Err(BspError::BadEntityLump("key without a value"))
+            };
+            properties.insert(key, value);
+        }
+
+        entities.push(try!(entity_from_properties(properties)));
+    }
+
+    let kinds: Vec<EntityKind> = entities.iter()
+        .map(|ent| classify_entity(ent, &entities))
+        .collect();
+    for (ent, kind) in entities.iter_mut().zip(kinds.into_iter()) {
+        ent.kind = kind;
+    }
+
+    Ok(entities)
+}
+
+fn parse_vec3(s: &str) -> Result<na::Vec3<f32>, BspError> {
+    let components = s.split(' ')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>();
+
+    match components {
+        Ok(ref c) if c.len() == 3 => Ok(na::Vec3::new(c[0], c[1], c[2])),
+        _ => Err(BspError::BadEntityLump("bad vector"))
+    }
+}
+
+fn entity_from_properties(properties: HashMap<String, String>) -> Result<Entity, BspError> {
+    let classname = match properties.get("classname") {
+        Some(classname) => classname.clone(),
+        None => return Err(BspError::BadEntityLump("entity without a classname"))
+    };
+
+    let model = match properties.get("model") {
+        Some(model) if model.starts_with("*") => {
+            match model[1..].parse::<u32>() {
+                Ok(idx) => Some(idx),
+                Err(_) => return Err(BspError::BadEntityLump("bad model reference"))
+            }
+        },
+        // worldspawn is implicitly model 0.
+        _ if classname == "worldspawn" => Some(0),
+        // Anything else refers to an external (md3) model, which we don't collide with.
+        _ => None
+    };
+
+    let origin = match properties.get("origin") {
+        Some(origin) => {
+            let o = try!(parse_vec3(origin));
+            Some(na::Pnt3::new(o.x, -o.z, o.y))
+        },
+        None => None
+    };
+
+    let angle = if let Some(angles) = properties.get("angles") {
+        try!(parse_vec3(angles)).y
+    } else if let Some(angle) = properties.get("angle") {
+        match angle.trim().parse::<f32>() {
+            Ok(angle) => angle,
+            Err(_) => return Err(BspError::BadEntityLump("bad angle"))
+        }
+    } else {
+        0.0
+    };
+
+    Ok(Entity {
+        classname: classname,
+        model: model,
+        origin: origin,
+        angle: angle,
+        target: properties.get("target").cloned(),
+        targetname: properties.get("targetname").cloned(),
+        path: None,
+        properties: properties,
+        kind: EntityKind::Other
     })
 }
 
+/// Works out what an entity means to the game, following its target if need be.
+/// Start lines, goals and checkpoints use the Defrag convention of a trigger_multiple
+/// targeting a target_startTimer, target_stopTimer or target_checkpoint.
+/// Jump pads and teleporters aim at whatever positioned entity they target,
+/// usually a target_position or misc_teleporter_dest.
+fn classify_entity(ent: &Entity, entities: &[Entity]) -> EntityKind {
+    let target = ent.target.as_ref().and_then(|target| {
+        entities.iter().find(|t| t.targetname.as_ref() == Some(target))
+    });
+    let targets = |classname: &str| {
+        target.map(|t| t.classname == classname).unwrap_or(false)
+    };
+
+    match &ent.classname[..] {
+        "worldspawn" => EntityKind::Worldspawn,
+        "trigger_hurt" => EntityKind::OutOfBounds,
+        "trigger_multiple" if targets("target_startTimer") => EntityKind::Start,
+        "trigger_multiple" if targets("target_stopTimer") => EntityKind::Goal,
+        "trigger_multiple" if targets("target_checkpoint") => EntityKind::Checkpoint,
+        "trigger_push" => match target.and_then(|t| t.origin) {
+            Some(origin) => EntityKind::JumpPad {
+                target: origin
+            },
+            None => EntityKind::Other
+        },
+        "func_door" | "func_plat" | "func_train" => EntityKind::Mover,
+        "trigger_teleport" => match target {
+            Some(&Entity { origin: Some(origin), angle, .. }) => EntityKind::Teleporter {
+                dest: origin,
+                yaw: angle_to_yaw(angle)
+            },
+            _ => EntityKind::Other
+        },
+        _ => EntityKind::Other
+    }
+}
+
+/// Turns a Quake 3 "angle" (degrees counterclockwise from +X, seen from above)
+/// into a yaw around our downwards Y axis.
+fn angle_to_yaw(angle: f32) -> f32 {
+    -(angle * std::f32::consts::PI / 180.0 + std::f32::consts::FRAC_PI_2)
+}
+
+fn find_spawnpoints(entities: &[Entity]) -> Vec<SpawnPoint> {
+    let mut spawnpoints = vec![];
+    for classname in ["info_player_start", "info_player_deathmatch"].iter() {
+        for ent in entities.iter().filter(|ent| ent.classname == *classname) {
+            if let Some(origin) = ent.origin {
+                spawnpoints.push(SpawnPoint {
+                    pos: origin,
+                    yaw: angle_to_yaw(ent.angle)
+                });
+            }
+        }
+    }
+    spawnpoints
+}
+
+const HEADER_LEN: usize = 8 + 17 * 8;
+
 struct Directory<'a> {
     entities: &'a [u8],
     textures: &'a [u8],
     planes: &'a [u8],
     nodes: &'a [u8],
     leaves: &'a [u8],
+    leaffaces: &'a [u8],
     leafbrushes: &'a [u8],
     models: &'a [u8],
     brushes: &'a [u8],
@@ -140,79 +520,105 @@ struct Directory<'a> {
     lightmaps: &'a [u8],
 }
 
-fn read_directory(data: &[u8]) -> byteorder::Result<Directory> {
-    let mut cursor = Cursor::new(data);
-
-    cursor.seek(SeekFrom::Start(8)).unwrap();
-    let entities_offset = try!(cursor.read_u32::<LittleEndian>());
-    let entities_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let textures_offset = try!(cursor.read_u32::<LittleEndian>());
-    let textures_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let planes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let planes_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let nodes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let nodes_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let leaves_offset = try!(cursor.read_u32::<LittleEndian>());
-    let leaves_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(8*1)).unwrap();
-    let leafbrushes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let leafbrushes_len = try!(cursor.read_u32::<LittleEndian>());
+fn read_directory(data: &[u8]) -> Result<Directory, BspError> {
+    if data.len() < 4 || &data[0..4] != b"IBSP" {
+        return Err(BspError::BadMagic);
+    }
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let models_offset = try!(cursor.read_u32::<LittleEndian>());
-    let models_len = try!(cursor.read_u32::<LittleEndian>());
+    let mut cursor = Cursor::new(data);
+    cursor.seek(SeekFrom::Start(4)).unwrap();
+    let version = try!(cursor.read_u32::<LittleEndian>());
+    if version != 46 && version != 47 {
+        return Err(BspError::UnsupportedVersion(version));
+    }
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let brushes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let brushes_len = try!(cursor.read_u32::<LittleEndian>());
+    let mut lumps = vec![];
+    for &name in LUMP_NAMES.iter() {
+        let offset = try!(cursor.read_u32::<LittleEndian>());
+        let len = try!(cursor.read_u32::<LittleEndian>());
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let brushsides_offset = try!(cursor.read_u32::<LittleEndian>());
-    let brushsides_len = try!(cursor.read_u32::<LittleEndian>());
+        let start = offset as usize;
+        let end = match start.checked_add(len as usize) {
+            Some(end) if end <= data.len() && start >= HEADER_LEN => end,
+            // Empty lumps don't get to point anywhere in particular.
+            _ if len == 0 && start <= data.len() => start,
+            _ => return Err(BspError::LumpOutOfBounds {
+                lump: name,
+                offset: offset,
+                len: len
+            })
+        };
+        lumps.push(&data[start..end]);
+    }
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let vertices_offset = try!(cursor.read_u32::<LittleEndian>());
-    let vertices_len = try!(cursor.read_u32::<LittleEndian>());
+    Ok(Directory {
+        entities: lumps[0],
+        textures: lumps[1],
+        planes: lumps[2],
+        nodes: lumps[3], 
+        leaves: lumps[4],
+        leaffaces: lumps[5],
+        leafbrushes: lumps[6],
+        models: lumps[7],
+        brushes: lumps[8],
+        brushsides: lumps[9],
+        vertices: lumps[10], 
+        meshverts: lumps[11], 
+        faces: lumps[13], 
+        lightmaps: lumps[14], 
+    })
+}
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let meshverts_offset = try!(cursor.read_u32::<LittleEndian>());
-    let meshverts_len = try!(cursor.read_u32::<LittleEndian>());
+const LUMP_NAMES: [&'static str; 17] = [
+    "entities",
+    "textures",
+    "planes",
+    "nodes",
+    "leaves",
+    "leaffaces",
+    "leafbrushes",
+    "models",
+    "brushes",
+    "brushsides",
+    "vertices",
+    "meshverts",
+    "effects",
+    "faces",
+    "lightmaps",
+    "lightvols",
+    "visdata",
+];
 
-    cursor.seek(SeekFrom::Current(8*1)).unwrap();
-    let faces_offset = try!(cursor.read_u32::<LittleEndian>());
-    let faces_len = try!(cursor.read_u32::<LittleEndian>());
+/// Splits a lump into elements of a fixed size, refusing to leave a partial one at the end.
+fn elements<'a>(data: &'a [u8], size: usize, lump: &'static str) -> Result<std::slice::Chunks<'a, u8>, BspError> {
+    if data.len() % size != 0 {
+        Err(BspError::BadLumpSize { lump: lump, len: data.len() as u32 })
+    } else {
+        Ok(data.chunks(size))
+    }
+}
 
-    cursor.seek(SeekFrom::Current(8*0)).unwrap();
-    let lightmaps_offset = try!(cursor.read_u32::<LittleEndian>());
-    let lightmaps_len = try!(cursor.read_u32::<LittleEndian>());
+/// Makes sure `index` refers to one of the `len` elements of `lump`.
+fn check_index(index: i64, len: usize, lump: &'static str) -> Result<usize, BspError> {
+    if index >= 0 && (index as u64) < len as u64 {
+        Ok(index as usize)
+    } else {
+        Err(BspError::BadIndex { lump: lump, index: index })
+    }
+}
 
-    Ok(Directory {
-        entities: &data[entities_offset as usize .. (entities_offset + entities_len) as usize],
-        textures: &data[textures_offset as usize .. (textures_offset + textures_len) as usize],
-        planes: &data[planes_offset as usize .. (planes_offset + planes_len) as usize],
-        nodes: &data[nodes_offset as usize .. (nodes_offset + nodes_len) as usize], 
-        leaves: &data[leaves_offset as usize .. (leaves_offset + leaves_len) as usize],
-        leafbrushes: &data[leafbrushes_offset as usize .. (leafbrushes_offset + leafbrushes_len) as usize],
-        models: &data[models_offset as usize .. (models_offset + models_len) as usize],
-        brushes: &data[brushes_offset as usize .. (brushes_offset + brushes_len) as usize],
-        brushsides: &data[brushsides_offset as usize .. (brushsides_offset + brushsides_len) as usize],
-        vertices: &data[vertices_offset as usize .. (vertices_offset + vertices_len) as usize], 
-        meshverts: &data[meshverts_offset as usize .. (meshverts_offset + meshverts_len) as usize], 
-        faces: &data[faces_offset as usize .. (faces_offset + faces_len) as usize], 
-        lightmaps: &data[lightmaps_offset as usize .. (lightmaps_offset + lightmaps_len) as usize], 
-    })
+/// Makes sure `count` elements starting at `start` all lie within the `len` elements of `lump`.
+fn check_range(start: i64, count: i64, len: usize, lump: &'static str) -> Result<std::ops::Range<usize>, BspError> {
+    if start < 0 || start > len as i64 {
+        Err(BspError::BadIndex { lump: lump, index: start })
+    } else if count < 0 || start + count > len as i64 {
+        Err(BspError::BadIndex { lump: lump, index: start + count })
+    } else {
+        Ok(start as usize .. (start + count) as usize)
+    }
 }
 
-fn read_plane(data: &[u8]) -> byteorder::Result<bsp::Plane> {
+fn read_plane(data: &[u8]) -> Result<bsp::Plane, BspError> {
     let mut cursor = Cursor::new(data);
 
     let n_x = try!(cursor.read_f32::<LittleEndian>()); 
@@ -225,134 +631,202 @@ fn read_plane(data: &[u8]) -> byteorder::Result<bsp::Plane> {
         dist: dist
     })
 }
-fn read_planes(data: &[u8]) -> byteorder::Result<Vec<bsp::Plane>> {
-    data.chunks(16)
+fn read_planes(data: &[u8]) -> Result<Vec<bsp::Plane>, BspError> {
+    try!(elements(data, 16, "planes"))
         .map(|chunk| read_plane(chunk))
         .collect()
 }
 
 
-fn read_node(data: &[u8], planes: &[bsp::Plane]) -> byteorder::Result<bsp::InnerNode> {
+fn read_node(data: &[u8], idx: usize, n_nodes: usize, planes: &[bsp::Plane], n_leaves: usize) -> Result<bsp::InnerNode, BspError> {
     let mut cursor = Cursor::new(data);
 
     let plane_id = try!(cursor.read_i32::<LittleEndian>()); 
     let front = try!(cursor.read_i32::<LittleEndian>()); 
     let back = try!(cursor.read_i32::<LittleEndian>()); 
 
+    // Children always come after their parents, which also rules out cycles.
+    for &child in [front, back].iter() {
+        if child >= 0 {
+            if child as usize <= idx || child as usize >= n_nodes {
+                return Err(BspError::BadIndex { lump: "nodes", index: child as i64 });
+            }
+        } else {
+            try!(check_index(-(child as i64) - 1, n_leaves, "leaves"));
+        }
+    }
+
     Ok(bsp::InnerNode {
-        plane: planes[plane_id as usize].clone(),
+        plane: planes[try!(check_index(plane_id as i64, planes.len(), "planes"))].clone(),
         pos: front as i32,
         neg: back as i32,
     })
 }
-fn read_nodes(data: &[u8], planes: &[bsp::Plane]) -> byteorder::Result<Vec<bsp::InnerNode>> {
-    data.chunks(36)
-        .map(|chunk| read_node(chunk, planes))
+fn read_nodes(data: &[u8], planes: &[bsp::Plane], n_leaves: usize) -> Result<Vec<bsp::InnerNode>, BspError> {
+    let n_nodes = data.len() / 36;
+    if n_nodes == 0 {
+        // Casts always start at the root node.
+        return Err(BspError::BadIndex { lump: "nodes", index: 0 });
+    }
+    try!(elements(data, 36, "nodes"))
+        .enumerate()
+        .map(|(idx, chunk)| read_node(chunk, idx, n_nodes, planes, n_leaves))
         .collect()
 }
 
-fn read_brushside(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> byteorder::Result<bsp::BrushSide> {
+fn read_brushside(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> Result<bsp::BrushSide, BspError> {
     let mut cursor = Cursor::new(data);
     let plane_id = try!(cursor.read_i32::<LittleEndian>());
     let texture_id = try!(cursor.read_i32::<LittleEndian>());
-    let tex = &textures[texture_id as usize];
+    let tex = &textures[try!(check_index(texture_id as i64, textures.len(), "textures"))];
     Ok(bsp::BrushSide {
-        plane: planes[plane_id as usize].clone(),
+        plane: planes[try!(check_index(plane_id as i64, planes.len(), "planes"))].clone(),
         contents: tex.contents,
         flags: tex.flags
     })
 }
 
-fn read_brushsides(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> byteorder::Result<Vec<bsp::BrushSide>> {
-    data.chunks(8)
+fn read_brushsides(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> Result<Vec<bsp::BrushSide>, BspError> {
+    try!(elements(data, 8, "brushsides"))
         .map(|chunk| read_brushside(chunk, planes, textures))
         .collect()
 }
 
-fn read_brushes(data: &[u8], brushsides: &[bsp::BrushSide]) -> byteorder::Result<Vec<bsp::Brush>> {
-    data.chunks(12)
-        .map(|chunk| read_brush(chunk, brushsides))
+fn read_brushes(data: &[u8], brushsides: &[bsp::BrushSide], textures: &[Texture]) -> Result<Vec<bsp::Brush>, BspError> {
+    try!(elements(data, 12, "brushes"))
+        .map(|chunk| read_brush(chunk, brushsides, textures))
         .collect()
 }
 
-fn read_brush(data: &[u8], brushsides: &[bsp::BrushSide]) -> byteorder::Result<bsp::Brush> {
+fn read_brush(data: &[u8], brushsides: &[bsp::BrushSide], textures: &[Texture]) -> Result<bsp::Brush, BspError> {
     let mut cursor = Cursor::new(data);
     let brushside = try!(cursor.read_i32::<LittleEndian>());
     let n_brushsides = try!(cursor.read_i32::<LittleEndian>());
-    Ok(bsp::Brush {
-        sides: brushsides[brushside as usize .. (brushside + n_brushsides) as usize].to_vec()
-    })
+    let texture = try!(cursor.read_i32::<LittleEndian>());
+    let sides = try!(check_range(brushside as i64, n_brushsides as i64, brushsides.len(), "brushsides"));
+    let tex = &textures[try!(check_index(texture as i64, textures.len(), "textures"))];
+    Ok(bsp::Brush::new(brushsides[sides].to_vec(), tex.contents))
 }
 
 
-fn read_leaf(data: &[u8]) -> byteorder::Result<bsp::Leaf> {
+fn read_leaf(data: &[u8], n_leafbrushes: usize) -> Result<bsp::Leaf, BspError> {
     let mut cursor = Cursor::new(data);
     cursor.seek(SeekFrom::Start(40)).unwrap();
 
     let leafbrush = try!(cursor.read_i32::<LittleEndian>()); 
-    let n_leafbrushes = try!(cursor.read_i32::<LittleEndian>()); 
+    let n_leafbrushes_in_leaf = try!(cursor.read_i32::<LittleEndian>()); 
+    try!(check_range(leafbrush as i64, n_leafbrushes_in_leaf as i64, n_leafbrushes, "leafbrushes"));
     Ok(bsp::Leaf {
         leafbrush: leafbrush,
-        n_leafbrushes: n_leafbrushes
+        n_leafbrushes: n_leafbrushes_in_leaf
     })
 }
 
-fn read_leaves(data: &[u8]) -> byteorder::Result<Vec<bsp::Leaf>> {
-    data.chunks(48)
-        .map(|chunk| read_leaf(chunk))
+fn read_leaves(data: &[u8], n_leafbrushes: usize) -> Result<Vec<bsp::Leaf>, BspError> {
+    try!(elements(data, 48, "leaves"))
+        .map(|chunk| read_leaf(chunk, n_leafbrushes))
+        .collect()
+}
+
+fn read_leafbrushes(data: &[u8], n_brushes: usize) -> Result<Vec<u32>, BspError> {
+    try!(elements(data, 4, "leafbrushes"))
+        .map(|chunk| {
+            let mut cursor = Cursor::new(chunk);
+            let brush = try!(cursor.read_i32::<LittleEndian>());
+            Ok(try!(check_index(brush as i64, n_brushes, "brushes")) as u32)
+        })
+        .collect()
+}
+
+/// Reads the range of leaffaces each leaf refers to.
+fn read_leaf_faces(data: &[u8], n_leaffaces: usize) -> Result<Vec<std::ops::Range<usize>>, BspError> {
+    try!(elements(data, 48, "leaves"))
+        .map(|chunk| {
+            let mut cursor = Cursor::new(chunk);
+            cursor.seek(SeekFrom::Start(32)).unwrap();
+
+            let leafface = try!(cursor.read_i32::<LittleEndian>()); 
+            let n_leaffaces_in_leaf = try!(cursor.read_i32::<LittleEndian>()); 
+            check_range(leafface as i64, n_leaffaces_in_leaf as i64, n_leaffaces, "leaffaces")
+        })
         .collect()
 }
 
-fn read_leafbrushes(data: &[u8]) -> byteorder::Result<Vec<u32>> {
-    data.chunks(4)
+fn read_leaffaces(data: &[u8], n_faces: usize) -> Result<Vec<u32>, BspError> {
+    try!(elements(data, 4, "leaffaces"))
         .map(|chunk| {
             let mut cursor = Cursor::new(chunk);
-            cursor.read_u32::<LittleEndian>()
+            let face = try!(cursor.read_i32::<LittleEndian>());
+            Ok(try!(check_index(face as i64, n_faces, "faces")) as u32)
         })
         .collect()
 }
 
-fn read_meshverts(data: &[u8]) -> byteorder::Result<Vec<u32>> {
-    data.chunks(4)
+fn read_meshverts(data: &[u8]) -> Result<Vec<u32>, BspError> {
+    try!(elements(data, 4, "meshverts"))
         .map(|chunk| {
             let mut cursor = Cursor::new(chunk);
-            cursor.read_u32::<LittleEndian>()
+            Ok(try!(cursor.read_u32::<LittleEndian>()))
         })
         .collect()
 }
 
+const FACE_POLYGON: i32 = 1;
+const FACE_PATCH: i32 = 2;
+const FACE_MESH: i32 = 3;
+const FACE_BILLBOARD: i32 = 4;
+
 #[derive(Debug)]
 struct Face {
     texture: i32,
+    kind: i32,
     lightmap: i32,
     vertex: i32,
     n_vertexes: i32,
     meshvert: i32,
     n_meshverts: i32,
+    /// Where a billboard is.
+    origin: na::Vec3<f32>,
+    /// Dimensions of a patch's grid of control points.
+    size: (i32, i32),
 }
 
-fn read_face(data: &[u8]) -> byteorder::Result<Face> {
+fn read_face(data: &[u8]) -> Result<Face, BspError> {
     let mut cursor = Cursor::new(data);
     let texture = try!(cursor.read_i32::<LittleEndian>()); 
-    cursor.seek(SeekFrom::Current(8)).unwrap();
+    cursor.seek(SeekFrom::Current(4)).unwrap();
+    let kind = try!(cursor.read_i32::<LittleEndian>()); 
     let vertex = try!(cursor.read_i32::<LittleEndian>()); 
     let n_vertexes = try!(cursor.read_i32::<LittleEndian>()); 
     let meshvert = try!(cursor.read_i32::<LittleEndian>()); 
     let n_meshverts = try!(cursor.read_i32::<LittleEndian>()); 
     let lightmap = try!(cursor.read_i32::<LittleEndian>()); 
 
+    // Billboards keep their position in what is usually the lightmap origin.
+    cursor.seek(SeekFrom::Start(48)).unwrap();
+    let o_x = try!(cursor.read_f32::<LittleEndian>());
+    let o_y = try!(cursor.read_f32::<LittleEndian>());
+    let o_z = try!(cursor.read_f32::<LittleEndian>());
+
+    cursor.seek(SeekFrom::Start(96)).unwrap();
+    let width = try!(cursor.read_i32::<LittleEndian>()); 
+    let height = try!(cursor.read_i32::<LittleEndian>()); 
+
     Ok(Face {
         texture: texture,
+        kind: kind,
         vertex: vertex,
         n_vertexes: n_vertexes,
         meshvert: meshvert,
         n_meshverts: n_meshverts, 
         lightmap: lightmap,
+        origin: na::Vec3::new(o_x, o_y, o_z),
+        size: (width, height),
     })
 }
 
-fn read_faces(data: &[u8]) -> byteorder::Result<Vec<Face>> {
-    data.chunks(104)
+fn read_faces(data: &[u8]) -> Result<Vec<Face>, BspError> {
+    try!(elements(data, 104, "faces"))
         .map(|chunk| read_face(chunk))
         .collect()
 }
@@ -364,7 +838,7 @@ struct Texture {
     contents: i32,
 }
 
-fn read_texture(data: &[u8]) -> byteorder::Result<Texture> {
+fn read_texture(data: &[u8]) -> Result<Texture, BspError> {
     let mut cursor = Cursor::new(data);
     let name = &data[0..64];
     let namelen = name.iter()
@@ -372,6 +846,7 @@ fn read_texture(data: &[u8]) -> byteorder::Result<Texture> {
         .unwrap_or(name.len());
     let name = String::from_utf8_lossy(&name[..namelen]).to_string();
 
+    cursor.seek(SeekFrom::Start(64)).unwrap();
     let flags = try!(cursor.read_i32::<LittleEndian>());
     let contents = try!(cursor.read_i32::<LittleEndian>());
 
@@ -382,8 +857,8 @@ fn read_texture(data: &[u8]) -> byteorder::Result<Texture> {
     })
 }
 
-fn read_textures(data: &[u8]) -> byteorder::Result<Vec<Texture>> {
-    data.chunks(72)
+fn read_textures(data: &[u8]) -> Result<Vec<Texture>, BspError> {
+    try!(elements(data, 72, "textures"))
         .map(|chunk| read_texture(chunk))
         .collect()
 }
@@ -392,8 +867,8 @@ fn read_textures(data: &[u8]) -> byteorder::Result<Vec<Texture>> {
 struct Lightmap {
     data: Vec<Vec<(u8, u8, u8)>>
 }
-fn read_lightmaps(data: &[u8]) -> byteorder::Result<Vec<Lightmap>> {
-    data.chunks(128*128*3)
+fn read_lightmaps(data: &[u8]) -> Result<Vec<Lightmap>, BspError> {
+    Ok(try!(try!(elements(data, 128*128*3, "lightmaps"))
         .map(|row| Ok(Lightmap { data: try!(row.chunks(128*3)
              .map(|col| col.chunks(3).map(|px| {
 
@@ -405,16 +880,48 @@ fn read_lightmaps(data: &[u8]) -> byteorder::Result<Vec<Lightmap>> {
              })
                   .collect::<byteorder::Result<Vec<_>>>())
              .collect::<Result<Vec<_>, _>>()) }))
-        .collect::<Result<Vec<_>, _>>()
+        .collect::<byteorder::Result<Vec<_>>>()))
 }
 
+#[derive(Copy, Clone)]
 struct Vertex {
     position: na::Vec3<f32>,
     texcoords: na::Vec2<f32>,
     lightmaptexcoords: na::Vec2<f32>,
     normal: na::Vec3<f32>,
 }
-fn read_vertex(data: &[u8]) -> byteorder::Result<Vertex> {
+impl Vertex {
+    fn engine_position(&self) -> na::Vec3<f32> {
+        na::Vec3::new(self.position.x, -self.position.z, self.position.y)
+    }
+    fn engine_normal(&self) -> na::Vec3<f32> {
+        na::Vec3::new(self.normal.x, -self.normal.z, self.normal.y)
+    }
+}
+// These let patches interpolate every attribute at once.
+impl Add for Vertex {
+    type Output = Vertex;
+    fn add(self, other: Vertex) -> Vertex {
+        Vertex {
+            position: self.position + other.position,
+            texcoords: self.texcoords + other.texcoords,
+            lightmaptexcoords: self.lightmaptexcoords + other.lightmaptexcoords,
+            normal: self.normal + other.normal,
+        }
+    }
+}
+impl Mul<f32> for Vertex {
+    type Output = Vertex;
+    fn mul(self, s: f32) -> Vertex {
+        Vertex {
+            position: self.position * s,
+            texcoords: self.texcoords * s,
+            lightmaptexcoords: self.lightmaptexcoords * s,
+            normal: self.normal * s,
+        }
+    }
+}
+fn read_vertex(data: &[u8]) -> Result<Vertex, BspError> {
     let mut cursor = Cursor::new(data);
     let p_x = try!(cursor.read_f32::<LittleEndian>());
     let p_y = try!(cursor.read_f32::<LittleEndian>());
@@ -436,25 +943,541 @@ fn read_vertex(data: &[u8]) -> byteorder::Result<Vertex> {
     })
 }
 
-fn read_vertices(data: &[u8]) -> byteorder::Result<Vec<Vertex>> {
-    data.chunks(44)
+fn read_vertices(data: &[u8]) -> Result<Vec<Vertex>, BspError> {
+    try!(elements(data, 44, "vertices"))
         .map(|chunk| read_vertex(chunk))
         .collect()
 }
 
-fn read_model(data: &[u8]) -> byteorder::Result<Model> {
+fn read_model(data: &[u8], n_brushes: usize) -> Result<Model, BspError> {
     let mut cursor = Cursor::new(data);
-    cursor.seek(SeekFrom::Start(32)).unwrap();
+    let mut bounds = [0.0; 6];
+    for b in bounds.iter_mut() {
+        *b = try!(cursor.read_f32::<LittleEndian>());
+    }
+    // Swapping y and z flips which end of the y range is the minimum.
+    let mins = na::Vec3::new(bounds[0], -bounds[5], bounds[1]);
+    let maxs = na::Vec3::new(bounds[3], -bounds[2], bounds[4]);
 
-    let brush = try!(cursor.read_u32::<LittleEndian>()); 
-    let n_brushes = try!(cursor.read_u32::<LittleEndian>()); 
+    cursor.seek(SeekFrom::Start(32)).unwrap();
+    let brush = try!(cursor.read_i32::<LittleEndian>()); 
+    let n_brushes_in_model = try!(cursor.read_i32::<LittleEndian>()); 
+    try!(check_range(brush as i64, n_brushes_in_model as i64, n_brushes, "brushes"));
     Ok(Model {
-        brush: brush,
-        n_brushes: n_brushes
+        mins: mins,
+        maxs: maxs,
+        brush: brush as u32,
+        n_brushes: n_brushes_in_model as u32
     })
 }
-fn read_models(data: &[u8]) -> byteorder::Result<Vec<Model>> {
-    data.chunks(40)
-        .map(|chunk| read_model(chunk))
+fn read_models(data: &[u8], n_brushes: usize) -> Result<Vec<Model>, BspError> {
+    try!(elements(data, 40, "models"))
+        .map(|chunk| read_model(chunk, n_brushes))
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use na::{self, Rotate};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use super::{
+        import,
+        import_surfaces,
+        parse_entities,
+        find_spawnpoints,
+        BspError,
+        HEADER_LEN,
+    };
+    use EntityKind;
+    use MASK_PLAYERSOLID;
+    use cast::Ray;
+
+    const ENTITIES: &'static str = r#"
+{
+"classname" "worldspawn"
+"message" "test map"
+}
+// the finish line
+{
+"classname" "trigger_multiple"
+"model" "*2"
+"target" "finish"
+}
+{
+"classname" "target_stopTimer"
+"targetname" "finish"
+"origin" "64 32 -16"
+}
+{
+"classname" "trigger_hurt"
+"model" "*1"
+}
+{
+"classname" "info_player_start"
+"origin" "0 0 24"
+"angle" "90"
+}
+{
+"classname" "trigger_push"
+"model" "*3"
+"target" "apex"
+}
+{
+"classname" "target_position"
+"targetname" "apex"
+"origin" "0 256 512"
+}
+{
+"classname" "trigger_teleport"
+"model" "*4"
+"target" "exit"
+}
+{
+"classname" "misc_teleporter_dest"
+"targetname" "exit"
+"origin" "128 0 0"
+"angle" "180"
+}
+"#;
+
+    #[test]
+    fn parses_entities() {
+        let ents = parse_entities(ENTITIES).unwrap();
+        assert_eq!(ents.len(), 9);
+
+        assert_eq!(ents[0].kind, EntityKind::Worldspawn);
+        assert_eq!(ents[0].model, Some(0));
+        assert_eq!(ents[0].properties.get("message").map(|m| &m[..]), Some("test map"));
+
+        assert_eq!(ents[1].kind, EntityKind::Goal);
+        assert_eq!(ents[1].model, Some(2));
+
+        assert_eq!(ents[2].kind, EntityKind::Other);
+        assert_eq!(ents[2].model, None);
+        let origin = ents[2].origin.unwrap();
+        assert_eq!((origin.x, origin.y, origin.z), (64.0, 16.0, 32.0));
+
+        assert_eq!(ents[3].kind, EntityKind::OutOfBounds);
+        assert_eq!(ents[3].model, Some(1));
+
+        assert_eq!(ents[4].angle, 90.0);
+
+        assert_eq!(ents[5].kind, EntityKind::JumpPad { target: na::Pnt3::new(0.0, -512.0, 256.0) });
+        match ents[7].kind {
+            EntityKind::Teleporter { dest, yaw } => {
+                assert_eq!((dest.x, dest.y, dest.z), (128.0, 0.0, 0.0));
+                // Facing -X.
+                let forward = na::UnitQuat::new(na::Vec3::new(0.0, yaw, 0.0)).rotate(&na::Vec3::new(0.0, 0.0, -1.0));
+                assert!(na::approx_eq(&forward, &na::Vec3::new(-1.0, 0.0, 0.0)));
+            },
+            ref kind => panic!("expected a teleporter, got {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn spawnpoints_face_their_angle() {
+        let ents = parse_entities(r#"
+{ "classname" "info_player_deathmatch" "origin" "8 0 0" "angle" "90" }
+{ "classname" "info_player_start" "origin" "0 0 24" }
+"#).unwrap();
+        let spawnpoints = find_spawnpoints(&ents);
+        assert_eq!(spawnpoints.len(), 2);
+
+        // info_player_start goes first, and Q3's +Z is our -Y.
+        assert_eq!(spawnpoints[0].pos.y, -24.0);
+
+        // Angle 0 faces Q3's +X, which is still +X for us...
+        let forward = spawnpoints[0].get_ang().rotate(&na::Vec3::new(0.0, 0.0, -1.0));
+        assert!(na::approx_eq(&forward, &na::Vec3::new(1.0, 0.0, 0.0)));
+        // ...and 90 faces Q3's +Y, which is our +Z.
+        let forward = spawnpoints[1].get_ang().rotate(&na::Vec3::new(0.0, 0.0, -1.0));
+        assert!(na::approx_eq(&forward, &na::Vec3::new(0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn rejects_malformed_entities() {
+        assert!(parse_entities("{ \"classname\" \"worldspawn\"").is_err());
+        assert!(parse_entities("{ \"classname\" }").is_err());
+        assert!(parse_entities("{ \"classname\" \"worldspawn }").is_err());
+        assert!(parse_entities("\"classname\" \"worldspawn\"").is_err());
+        assert!(parse_entities("{ \"origin\" \"0 0 0\" }").is_err());
+        assert!(parse_entities("{ \"classname\" \"info_null\" \"origin\" \"0 0\" }").is_err());
+    }
+
+    fn i32s(values: &[i32]) -> Vec<u8> {
+        let mut data = vec![];
+        for &value in values {
+            data.write_i32::<LittleEndian>(value).unwrap();
+        }
+        data
+    }
+
+    fn f32s(values: &[f32]) -> Vec<u8> {
+        let mut data = vec![];
+        for &value in values {
+            data.write_f32::<LittleEndian>(value).unwrap();
+        }
+        data
+    }
+
+    fn set_i32(lump: &mut Vec<u8>, idx: usize, value: i32) {
+        for (i, b) in i32s(&[value]).into_iter().enumerate() {
+            lump[idx * 4 + i] = b;
+        }
+    }
+
+    /// A tiny but complete map: a single floor brush, in a tree with one node.
+    fn valid_lumps() -> Vec<Vec<u8>> {
+        let mut lumps = vec![vec![]; 17];
+
+        lumps[0] = b"{\n\"classname\" \"worldspawn\"\n}\n\0".to_vec();
+
+        let mut texture = b"textures/common/floor".to_vec();
+        while texture.len() < 64 {
+            texture.push(0);
+        }
+        texture.extend(i32s(&[0, 1]).into_iter());
+        lumps[1] = texture;
+
+        lumps[2] = f32s(&[
+            1.0, 0.0, 0.0, 64.0,
+            -1.0, 0.0, 0.0, 64.0,
+            0.0, 1.0, 0.0, 64.0,
+            0.0, -1.0, 0.0, 64.0,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, -1.0, 16.0,
+        ]);
+        lumps[3] = i32s(&[4, -1, -2, -64, -64, -16, 64, 64, 0]);
+
+        let leaf = i32s(&[0, 0, -64, -64, -16, 64, 64, 0, 0, 0, 0, 1]);
+        lumps[4] = leaf.iter().chain(leaf.iter()).cloned().collect();
+
+        lumps[6] = i32s(&[0]);
+
+        let mut model = f32s(&[-64.0, -64.0, -16.0, 64.0, 64.0, 0.0]);
+        model.extend(i32s(&[0, 0, 0, 1]).into_iter());
+        lumps[7] = model;
+
+        lumps[8] = i32s(&[0, 6, 0]);
+        lumps[9] = i32s(&[0, 0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0]);
+
+        lumps
+    }
+
+    /// `valid_lumps`, plus a 3x3 patch in the front leaf: a ridge along Q3's y
+    /// axis, curving up from the floor at x = -32 and 32 to a height of 32 at x = 0.
+    fn patch_lumps() -> Vec<Vec<u8>> {
+        let mut lumps = valid_lumps();
+
+        // The front leaf has the patch's face.
+        set_i32(&mut lumps[4], 8, 0);
+        set_i32(&mut lumps[4], 9, 1);
+        lumps[5] = i32s(&[0]);
+
+        for y in 0..3 {
+            for x in 0..3 {
+                let height = if x == 1 { 64.0 } else { 0.0 };
+                let position = [(x as f32 - 1.0) * 32.0, (y as f32 - 1.0) * 32.0, height];
+                lumps[10].extend(f32s(&position).into_iter());
+                lumps[10].extend(f32s(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0]).into_iter());
+                lumps[10].extend([255; 4].iter().cloned());
+            }
+        }
+
+        lumps[13] = face(2, [0.0; 3]);
+        set_i32(&mut lumps[13], 4, 9);
+        set_i32(&mut lumps[13], 24, 3);
+        set_i32(&mut lumps[13], 25, 3);
+        lumps
+    }
+
+    fn drop_onto(map: &::Map, x: f32, halfextents: na::Vec3<f32>) -> ::cast::CastResult {
+        let ray = Ray {
+            // Off the edges the tessellated triangles share.
+            orig: na::Pnt3::new(x, -128.0, 4.0),
+            dir: na::Vec3::new(0.0, 256.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        };
+        map.cast_ray(&ray).unwrap()
+    }
+
+    /// Lays out a BSP file. Empty lumps point at the very end of the file.
+    fn build_bsp(lumps: &[Vec<u8>]) -> Vec<u8> {
+        let total = lumps.iter().fold(HEADER_LEN, |total, lump| total + lump.len());
+
+        let mut data = b"IBSP".to_vec();
+        data.write_i32::<LittleEndian>(46).unwrap();
+        let mut offset = HEADER_LEN;
+        for lump in lumps {
+            if lump.is_empty() {
+                data.write_u32::<LittleEndian>(total as u32).unwrap();
+            } else {
+                data.write_u32::<LittleEndian>(offset as u32).unwrap();
+            }
+            data.write_u32::<LittleEndian>(lump.len() as u32).unwrap();
+            offset += lump.len();
+        }
+        for lump in lumps {
+            data.extend(lump.iter().cloned());
+        }
+        data
+    }
+
+    /// A face of the given type using texture 0, with `origin` where billboards keep it.
+    fn face(kind: i32, origin: [f32; 3]) -> Vec<u8> {
+        let mut face = i32s(&[0, -1, kind, 0, 0, 0, 0, -1, 0, 0, 0, 0]);
+        face.extend(f32s(&origin).into_iter());
+        face.extend(f32s(&[0.0; 9]).into_iter());
+        face.extend(i32s(&[0, 0]).into_iter());
+        face
+    }
+
+    fn corrupt_lump<F: Fn(&mut Vec<u8>)>(lump: usize, f: F) -> Vec<u8> {
+        let mut lumps = valid_lumps();
+        f(&mut lumps[lump]);
+        build_bsp(&lumps)
+    }
+
+    fn corrupt_patch_lump<F: Fn(&mut Vec<u8>)>(lump: usize, f: F) -> Vec<u8> {
+        let mut lumps = patch_lumps();
+        f(&mut lumps[lump]);
+        build_bsp(&lumps)
+    }
+
+    /// Every valid file the corruption tests start from.
+    fn fixtures() -> Vec<Vec<u8>> {
+        vec![build_bsp(&valid_lumps()), build_bsp(&patch_lumps())]
+    }
+
+    /// xorshift, so that failures are reproducible.
+    fn xorshift(seed: u32) -> Box<FnMut() -> u32> {
+        let mut state = seed;
+        Box::new(move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        })
+    }
+
+    macro_rules! assert_bsp_err {
+        ($data: expr, $pat: pat) => {
+            match import(&$data) {
+                Err($pat) => (),
+                Err(e) => panic!("Wrong error: {:?}", e),
+                Ok(_) => panic!("Corrupt BSP imported successfully")
+            }
+        }
+    }
+
+    #[test]
+    fn imports_valid_bsp() {
+        let map = import(&build_bsp(&valid_lumps())).unwrap();
+        assert_eq!(map.bsp.brushes.len(), 1);
+        assert_eq!(map.bsp.brushes[0].sides.len(), 6);
+        // Contents and flags come from the texture.
+        assert_eq!(map.bsp.brushes[0].contents, 1);
+        assert!(map.bsp.brushes[0].sides.iter().all(|side| side.contents == 1 && side.flags == 0));
+        assert_eq!(map.bsp.leaves.len(), 2);
+        assert_eq!(map.entities.len(), 1);
+    }
+
+    #[test]
+    fn collides_with_curved_patches() {
+        let map = import(&build_bsp(&patch_lumps())).unwrap();
+        assert!(map.bsp.brushes.len() > 1);
+
+        // The top of the ridge, at Q3 height 32...
+        let result = drop_onto(&map, 0.25, na::zero());
+        assert!(result.brush.unwrap() > 0);
+        assert!(result.norm.y < 0.0);
+        assert!(na::approx_eq_eps(&result.endpos.y, &-32.0, &0.5));
+        // ...and a point partway down its side, where it's curved.
+        let result = drop_onto(&map, 16.25, na::zero());
+        assert!(na::approx_eq_eps(&result.endpos.y, &-24.0, &0.5));
+        // Past the edge of the patch is the floor.
+        let result = drop_onto(&map, 48.0, na::zero());
+        assert_eq!(result.brush, Some(0));
+
+        // A box comes to rest on top of the ridge...
+        let halfextents = na::Vec3::new(8.0, 8.0, 8.0);
+        let result = drop_onto(&map, 0.0, halfextents);
+        assert!(result.brush.unwrap() > 0);
+        assert!(!result.start_solid);
+        assert!(na::approx_eq_eps(&result.endpos.y, &-40.0, &0.5));
+
+        // ...and stays there.
+        let ray = Ray {
+            orig: result.endpos,
+            dir: na::Vec3::new(0.0, 4.0, 0.0),
+            halfextents: halfextents,
+            mask: MASK_PLAYERSOLID
+        };
+        let rest = map.cast_ray(&ray).unwrap();
+        assert!(!rest.start_solid);
+        assert!(rest.toi < 0.1);
+        assert!(rest.norm.y < 0.0);
+    }
+
+    #[test]
+    fn imports_billboards_and_reports_unknown_faces() {
+        let mut lumps = valid_lumps();
+        lumps[13] = face(4, [8.0, 16.0, 32.0]);
+        lumps[13].extend(face(5, [0.0, 0.0, 0.0]).into_iter());
+        let surfaces = import_surfaces(&build_bsp(&lumps), 0).unwrap();
+
+        assert_eq!(surfaces.billboards.len(), 1);
+        assert_eq!(surfaces.billboards[0].position, [8.0, -32.0, 16.0]);
+        assert_eq!(surfaces.billboards[0].texture, 0);
+        // Neither of them has any geometry, and the unknown one is reported.
+        assert!(surfaces.faces.is_empty());
+        assert!(surfaces.indices.is_empty());
+        assert_eq!(surfaces.skipped_faces, vec![1]);
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut data = build_bsp(&valid_lumps());
+        data[0] = b'X';
+        assert_bsp_err!(data, BspError::BadMagic);
+
+        let mut data = build_bsp(&valid_lumps());
+        data[4] = 45;
+        assert_bsp_err!(data, BspError::UnsupportedVersion(45));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        for data in fixtures() {
+            for len in 0..data.len() {
+                assert!(import(&data[..len]).is_err(), "Imported a BSP truncated to {} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_lumps() {
+        let valid = build_bsp(&valid_lumps());
+
+        // planes is lump 2, its offset is at 8 + 2 * 8.
+        let mut data = valid.clone();
+        for i in 24..28 {
+            data[i] = 0xff;
+        }
+        assert_bsp_err!(data, BspError::LumpOutOfBounds { lump: "planes", .. });
+
+        // ...and its length right after that.
+        let mut data = valid.clone();
+        data[28] = 0xff;
+        data[29] = 0xff;
+        assert_bsp_err!(data, BspError::LumpOutOfBounds { lump: "planes", .. });
+
+        // Lumps can't overlap the header either.
+        let mut data = valid.clone();
+        for (i, b) in i32s(&[8]).into_iter().enumerate() {
+            data[24 + i] = b;
+        }
+        assert_bsp_err!(data, BspError::LumpOutOfBounds { lump: "planes", offset: 8, .. });
+
+        assert_bsp_err!(corrupt_lump(2, |l| l.push(0)), BspError::BadLumpSize { lump: "planes", .. });
+        assert_bsp_err!(corrupt_lump(1, |l| l.truncate(70)), BspError::BadLumpSize { lump: "textures", .. });
+        assert_bsp_err!(corrupt_lump(3, |l| l.clear()), BspError::BadIndex { lump: "nodes", .. });
+        assert_bsp_err!(corrupt_patch_lump(10, |l| { l.pop(); }), BspError::BadLumpSize { lump: "vertices", .. });
+        assert_bsp_err!(corrupt_patch_lump(13, |l| l.truncate(100)), BspError::BadLumpSize { lump: "faces", .. });
+    }
+
+    #[test]
+    fn rejects_bad_indices() {
+        // Brushsides pointing at missing planes and textures.
+        assert_bsp_err!(corrupt_lump(9, |l| set_i32(l, 10, 6)), BspError::BadIndex { lump: "planes", index: 6 });
+        assert_bsp_err!(corrupt_lump(9, |l| set_i32(l, 1, -1)), BspError::BadIndex { lump: "textures", index: -1 });
+
+        // Brushes with too many or a negative number of sides.
+        assert_bsp_err!(corrupt_lump(8, |l| set_i32(l, 1, 7)), BspError::BadIndex { lump: "brushsides", .. });
+        assert_bsp_err!(corrupt_lump(8, |l| set_i32(l, 1, -1)), BspError::BadIndex { lump: "brushsides", .. });
+        assert_bsp_err!(corrupt_lump(8, |l| set_i32(l, 0, -5)), BspError::BadIndex { lump: "brushsides", .. });
+        assert_bsp_err!(corrupt_lump(8, |l| set_i32(l, 2, 1)), BspError::BadIndex { lump: "textures", index: 1 });
+
+        // Leafbrushes pointing at missing brushes, and leaves at missing leafbrushes.
+        assert_bsp_err!(corrupt_lump(6, |l| set_i32(l, 0, 1)), BspError::BadIndex { lump: "brushes", index: 1 });
+        assert_bsp_err!(corrupt_lump(4, |l| set_i32(l, 11, 2)), BspError::BadIndex { lump: "leafbrushes", .. });
+        assert_bsp_err!(corrupt_lump(4, |l| set_i32(l, 22, 1)), BspError::BadIndex { lump: "leafbrushes", .. });
+
+        // Nodes pointing at missing planes, leaves or nodes, or back at themselves.
+        assert_bsp_err!(corrupt_lump(3, |l| set_i32(l, 0, 99)), BspError::BadIndex { lump: "planes", index: 99 });
+        assert_bsp_err!(corrupt_lump(3, |l| set_i32(l, 1, -3)), BspError::BadIndex { lump: "leaves", index: 2 });
+        assert_bsp_err!(corrupt_lump(3, |l| set_i32(l, 2, 0)), BspError::BadIndex { lump: "nodes", index: 0 });
+        assert_bsp_err!(corrupt_lump(3, |l| set_i32(l, 2, 1)), BspError::BadIndex { lump: "nodes", index: 1 });
+
+        // Models with brushes that don't exist.
+        assert_bsp_err!(corrupt_lump(7, |l| set_i32(l, 9, 2)), BspError::BadIndex { lump: "brushes", .. });
+
+        // Entities using models that don't exist.
+        assert_bsp_err!(corrupt_lump(0, |l| {
+            *l = b"{ \"classname\" \"worldspawn\" }\n{ \"classname\" \"trigger_hurt\" \"model\" \"*1\" }\0".to_vec();
+        }), BspError::BadIndex { lump: "models", index: 1 });
+
+        // Leaves with leaffaces that don't exist, and leaffaces with missing faces.
+        assert_bsp_err!(corrupt_patch_lump(4, |l| set_i32(l, 9, 2)), BspError::BadIndex { lump: "leaffaces", index: 2 });
+        assert_bsp_err!(corrupt_patch_lump(5, |l| set_i32(l, 0, 1)), BspError::BadIndex { lump: "faces", index: 1 });
+
+        // Patches with missing textures or vertices...
+        assert_bsp_err!(corrupt_patch_lump(13, |l| set_i32(l, 0, 1)), BspError::BadIndex { lump: "textures", index: 1 });
+        assert_bsp_err!(corrupt_patch_lump(13, |l| set_i32(l, 3, 1)), BspError::BadIndex { lump: "vertices", index: 10 });
+        // ...or whose control points don't make up 3x3 patches.
+        assert_bsp_err!(corrupt_patch_lump(13, |l| set_i32(l, 24, 2)),
+                        BspError::BadPatchSize { face: 0, width: 2, height: 3 });
+        assert_bsp_err!(corrupt_patch_lump(13, |l| set_i32(l, 4, 8)),
+                        BspError::BadPatchSize { face: 0, width: 3, height: 3 });
+    }
+
+    #[test]
+    fn rejects_random_bad_indices() {
+        // Every field of the patch fixture that refers to something else, as
+        // (lump, element size in i32s, field).
+        let fields = [
+            (3, 9, 0), (3, 9, 1), (3, 9, 2),
+            (4, 12, 8), (4, 12, 9), (4, 12, 10), (4, 12, 11),
+            (5, 1, 0),
+            (6, 1, 0),
+            (7, 10, 8), (7, 10, 9),
+            (8, 3, 0), (8, 3, 1), (8, 3, 2),
+            (9, 2, 0), (9, 2, 1),
+            (13, 26, 0), (13, 26, 3), (13, 26, 4),
+        ];
+        let mut next = xorshift(0x8765_4321);
+        for &(lump, size, field) in fields.iter() {
+            for _ in 0..20 {
+                let mut lumps = patch_lumps();
+                let n_elements = lumps[lump].len() / (size * 4);
+                let element = next() as usize % n_elements;
+                // Far past the end of any lump, one way or the other.
+                let value = 1000 + (next() % 1000) as i32;
+                let value = if next() % 2 == 0 { value } else { -value };
+                set_i32(&mut lumps[lump], element * size + field, value);
+                assert!(import(&build_bsp(&lumps)).is_err(),
+                        "Imported a BSP with field {} of element {} of lump {} set to {}", field, element, lump, value);
+            }
+        }
+    }
+
+    #[test]
+    fn survives_random_corruption() {
+        let mut next = xorshift(0x1234_5678);
+        for valid in fixtures() {
+            for _ in 0..2000 {
+                let mut data = valid.clone();
+                let n_flips = 1 + next() % 8;
+                for _ in 0..n_flips {
+                    let idx = next() as usize % data.len();
+                    data[idx] ^= 1 << (next() % 8);
+                }
+                // Flipped floats can still make a valid map, so either result is
+                // fine as long as it doesn't panic. rejects_random_bad_indices
+                // covers the corruption that has to be an error.
+                let _ = import(&data);
+            }
+        }
+    }
+}